@@ -1,10 +1,54 @@
 use crate::error::ApiError;
-use actix_web::{get, post, web};
+use actix_web::{get, http::header, patch, post, put, web, HttpMessage, HttpRequest, HttpResponse};
 use bm_lib::permissions::Permission;
 use bm_lib::{discord::Id, model::Config};
 use tracing::instrument;
 
-use crate::{auth::AuthenticatedUser, State};
+use crate::{auth::AuthenticatedUser, crypto, templates, State};
+
+/// Strong ETag for a config: the digest of its canonical JSON, with object
+/// keys sorted so equal configs always hash the same.
+pub fn config_etag(config: &Config) -> Result<String, ApiError> {
+    let canonical = serde_json::to_value(config)
+        .and_then(|value| serde_json::to_vec(&value))
+        .map_err(|e| ApiError::Internal(format!("Failed to serialize config: {e}")))?;
+    Ok(format!("\"{}\"", crypto::digest(&canonical)))
+}
+
+/// Require an `If-Match` header naming the current version of `current`, so an
+/// edit based on a stale copy can't overwrite someone else's changes. Pass
+/// `current` on to [`State::update_config`] as the edit's base, which checks
+/// it again atomically with the write.
+pub fn require_if_match(req: &HttpRequest, current: &Config) -> Result<(), ApiError> {
+    let etag = config_etag(current)?;
+    let if_match = req
+        .headers()
+        .get(header::IF_MATCH)
+        .ok_or_else(|| {
+            ApiError::PreconditionRequired(
+                "If-Match header with the config ETag is required".into(),
+            )
+        })?
+        .to_str()
+        .map_err(|_| ApiError::BadRequest("Invalid If-Match header".into()))?;
+
+    let matches = if_match
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag == etag);
+    if !matches {
+        return Err(ApiError::PreconditionFailed(etag));
+    }
+
+    Ok(())
+}
+
+/// Respond with a config and its ETag.
+pub fn config_response(config: &Config) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok()
+        .insert_header((header::ETAG, config_etag(config)?))
+        .json(config))
+}
 
 #[get("/api/config/{id}")]
 #[instrument(skip(state, user), fields(user_id = %user.user_id))]
@@ -12,7 +56,7 @@ pub async fn get_config(
     state: web::Data<State>,
     id: web::Path<String>,
     user: AuthenticatedUser,
-) -> Result<web::Json<Config>, ApiError> {
+) -> Result<HttpResponse, ApiError> {
     let id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".to_string()))?;
 
     let config = match state.get_config(&id).await? {
@@ -32,17 +76,20 @@ pub async fn get_config(
         return Err(ApiError::Forbidden("Insufficient permissions".to_string()));
     }
 
-    Ok(web::Json(config))
+    config_response(&config)
 }
 
+/// `POST /api/config/{id}` - replace a guild's configuration. Requires an
+/// `If-Match` header with the ETag of the config being replaced.
 #[post("/api/config/{id}")]
-#[instrument(skip(state, user, config), fields(user_id = %user.user_id))]
+#[instrument(skip(state, user, req, config), fields(user_id = %user.user_id))]
 pub async fn post_config(
     state: web::Data<State>,
     id: web::Path<String>,
+    req: HttpRequest,
     config: web::Json<Config>,
     user: AuthenticatedUser,
-) -> Result<web::Json<Config>, ApiError> {
+) -> Result<HttpResponse, ApiError> {
     let id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".to_string()))?;
     let update = config.into_inner();
 
@@ -69,7 +116,123 @@ pub async fn post_config(
         return Err(ApiError::Forbidden("Insufficient permissions".to_string()));
     }
 
-    let updated = state.update_config(&id, &update).await?;
+    require_if_match(&req, &config)?;
+    state.validate_config(&update, &guild).await?;
+    state
+        .check_escalation(&guild, &config, &update, &user)
+        .await?;
+
+    let updated = state
+        .update_config(&id, &config, &update, &user.user_id, None)
+        .await?;
+
+    config_response(&updated)
+}
+
+/// `PUT /api/config/{id}` - set up a guild that has no config yet, starting
+/// from the default template. Open to the guild owner and members whose
+/// Discord permissions grant config editing (e.g. administrators).
+#[put("/api/config/{id}")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn init_config(
+    state: web::Data<State>,
+    id: web::Path<String>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".to_string()))?;
+
+    let guild = state
+        .get_guild(&id)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Guild not found".into()))?;
+
+    if state.get_config(&id).await?.is_some() {
+        return Err(ApiError::Conflict("Guild already has a config".to_string()));
+    }
+
+    // The default template inherits Discord permissions and has no groups, so
+    // this grants exactly the owner and members Discord lets manage the guild.
+    let config = templates::default_config(&id)?;
+    if !state
+        .check_permission(&config, Some(&guild), &user, Permission::CONFIG_EDIT)
+        .await?
+    {
+        return Err(ApiError::Forbidden("Insufficient permissions".to_string()));
+    }
+
+    let created = state
+        .create_config(&config, &user.user_id, Some("Initial setup"))
+        .await?;
+
+    Ok(HttpResponse::Created()
+        .insert_header((header::ETAG, config_etag(&created)?))
+        .json(created))
+}
+
+/// Content type of an RFC 6902 JSON Patch body.
+const JSON_PATCH_CONTENT_TYPE: &str = "application/json-patch+json";
+
+/// Apply a patch body to `config`: an RFC 6902 JSON Patch when sent as
+/// `application/json-patch+json`, otherwise an RFC 7396 merge patch.
+fn apply_config_patch(
+    config: &Config,
+    content_type: &str,
+    body: &[u8],
+) -> Result<Config, ApiError> {
+    let mut value = serde_json::to_value(config)
+        .map_err(|e| ApiError::Internal(format!("Failed to serialize config: {e}")))?;
+
+    if content_type == JSON_PATCH_CONTENT_TYPE {
+        let patch: json_patch::Patch = serde_json::from_slice(body)
+            .map_err(|e| ApiError::BadRequest(format!("Invalid JSON Patch: {e}")))?;
+        json_patch::patch(&mut value, &patch)
+            .map_err(|e| ApiError::BadRequest(format!("Failed to apply JSON Patch: {e}")))?;
+    } else {
+        let patch: serde_json::Value = serde_json::from_slice(body)
+            .map_err(|e| ApiError::BadRequest(format!("Invalid merge patch: {e}")))?;
+        json_patch::merge(&mut value, &patch);
+    }
+
+    serde_json::from_value(value)
+        .map_err(|e| ApiError::BadRequest(format!("Patched config is invalid: {e}")))
+}
+
+/// `PATCH /api/config/{id}` - update part of a guild's configuration.
+///
+/// Accepts an RFC 7396 merge patch (`application/merge-patch+json` or
+/// `application/json`) or an RFC 6902 JSON Patch (`application/json-patch+json`),
+/// applied to the stored config. Requires an `If-Match` header with the
+/// config's current ETag.
+#[patch("/api/config/{id}")]
+#[instrument(skip(state, user, req, body), fields(user_id = %user.user_id))]
+pub async fn patch_config(
+    state: web::Data<State>,
+    id: web::Path<String>,
+    req: HttpRequest,
+    body: web::Bytes,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".to_string()))?;
+
+    let (guild, config) = state
+        .require_guild_permission(&user, &id, Permission::CONFIG_EDIT)
+        .await?;
+    require_if_match(&req, &config)?;
+
+    let update = apply_config_patch(&config, req.content_type(), &body)?;
+    if update.id != id {
+        return Err(ApiError::BadRequest(
+            "Config ID cannot be changed".to_string(),
+        ));
+    }
+    state.validate_config(&update, &guild).await?;
+    state
+        .check_escalation(&guild, &config, &update, &user)
+        .await?;
+
+    let updated = state
+        .update_config(&id, &config, &update, &user.user_id, None)
+        .await?;
 
-    Ok(web::Json(updated))
+    config_response(&updated)
 }
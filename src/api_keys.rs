@@ -0,0 +1,381 @@
+use actix_web::{delete, get, post, web};
+use bm_lib::{discord::Id, permissions::Permission};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::{auth::AuthenticatedUser, crypto, error::ApiError, State};
+
+/// Prefix identifying API keys in an `Authorization: Bearer` header.
+pub const API_KEY_PREFIX: &str = "bm_";
+
+/// A long-lived automation credential. It acts as the user who created it,
+/// but only inside one guild and only with the permissions it was granted.
+///
+/// The bm-lib database has no table for API keys, so they are persisted
+/// without a TTL in a Redis hash under the API's prefix, with a per-guild set
+/// of key IDs. Both are updated in one transaction. Only a hash of the secret
+/// is stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub guild_id: Id,
+    pub created_by: Id,
+    pub permissions: Permission,
+    /// Unix timestamp (seconds).
+    pub created_at: i64,
+    pub secret_hash: String,
+}
+
+/// Public view of an [`ApiKey`], without the secret hash.
+#[derive(Debug, Serialize)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub name: String,
+    pub guild_id: Id,
+    pub created_by: Id,
+    pub permissions: Permission,
+    pub created_at: i64,
+}
+
+impl From<ApiKey> for ApiKeyInfo {
+    fn from(key: ApiKey) -> Self {
+        Self {
+            id: key.id,
+            name: key.name,
+            guild_id: key.guild_id,
+            created_by: key.created_by,
+            permissions: key.permissions,
+            created_at: key.created_at,
+        }
+    }
+}
+
+/// What an API key is allowed to do; attached to [`AuthenticatedUser`].
+#[derive(Debug, Clone)]
+pub struct ApiKeyScope {
+    pub key_id: String,
+    pub guild_id: Id,
+    pub permissions: Permission,
+}
+
+impl From<&ApiKey> for ApiKeyScope {
+    fn from(key: &ApiKey) -> Self {
+        Self {
+            key_id: key.id.clone(),
+            guild_id: key.guild_id,
+            permissions: key.permissions,
+        }
+    }
+}
+
+/// Hash of every API key record, keyed by key ID.
+const API_KEYS_HASH: &str = "api_keys";
+
+#[inline]
+fn guild_api_keys_index(guild_id: &Id) -> String {
+    format!("api_keys:{}", guild_id)
+}
+
+/// Split a `bm_{key_id}.{secret}` token into its key ID and secret.
+fn parse_api_key_token(token: &str) -> Option<(&str, &str)> {
+    token
+        .strip_prefix(API_KEY_PREFIX)?
+        .split_once('.')
+        .filter(|(id, secret)| !id.is_empty() && !secret.is_empty())
+}
+
+fn parse_api_key(value: &str) -> Result<ApiKey, ApiError> {
+    serde_json::from_str(value)
+        .map_err(|e| ApiError::Internal(format!("Invalid API key record: {e}")))
+}
+
+impl State {
+    #[instrument(skip(self))]
+    pub async fn get_api_key(&self, key_id: &str) -> Result<Option<ApiKey>, ApiError> {
+        self.store
+            .hash_get(API_KEYS_HASH, key_id)
+            .await?
+            .map(|value| parse_api_key(&value))
+            .transpose()
+    }
+
+    /// A guild's API keys, oldest first.
+    #[instrument(skip(self))]
+    pub async fn get_guild_api_keys(&self, guild_id: &Id) -> Result<Vec<ApiKey>, ApiError> {
+        let mut keys = self
+            .store
+            .indexed_values(API_KEYS_HASH, &guild_api_keys_index(guild_id))
+            .await?
+            .iter()
+            .map(|value| parse_api_key(value))
+            .collect::<Result<Vec<_>, _>>()?;
+        keys.sort_by(|a, b| {
+            a.created_at
+                .cmp(&b.created_at)
+                .then_with(|| a.id.cmp(&b.id))
+        });
+        Ok(keys)
+    }
+
+    /// Create a key and return it with the plaintext token, which is never
+    /// stored and can't be recovered later.
+    #[instrument(skip(self))]
+    pub async fn create_api_key(
+        &self,
+        guild_id: &Id,
+        created_by: &Id,
+        name: &str,
+        permissions: Permission,
+    ) -> Result<(ApiKey, String), ApiError> {
+        let secret = crypto::random_token(32);
+        let api_key = ApiKey {
+            id: Uuid::new_v4().simple().to_string(),
+            name: name.to_string(),
+            guild_id: *guild_id,
+            created_by: *created_by,
+            permissions,
+            created_at: Utc::now().timestamp(),
+            secret_hash: crypto::hash_token(&secret),
+        };
+
+        let value = serde_json::to_string(&api_key)
+            .map_err(|e| ApiError::Internal(format!("Failed to serialize API key: {e}")))?;
+        self.store
+            .insert_indexed(
+                API_KEYS_HASH,
+                &guild_api_keys_index(guild_id),
+                &api_key.id,
+                &value,
+            )
+            .await?;
+
+        let token = format!("{}{}.{}", API_KEY_PREFIX, api_key.id, secret);
+        Ok((api_key, token))
+    }
+
+    #[instrument(skip(self))]
+    pub async fn revoke_api_key(&self, guild_id: &Id, key_id: &str) -> Result<bool, ApiError> {
+        let in_guild = self
+            .get_api_key(key_id)
+            .await?
+            .is_some_and(|key| key.guild_id == *guild_id);
+        if !in_guild {
+            return Ok(false);
+        }
+
+        Ok(self
+            .store
+            .remove_indexed(API_KEYS_HASH, &guild_api_keys_index(guild_id), key_id)
+            .await?)
+    }
+
+    /// Resolve an `Authorization: Bearer bm_...` token to its key.
+    #[instrument(skip(self, token))]
+    pub async fn authenticate_api_key(&self, token: &str) -> Result<ApiKey, ApiError> {
+        let (key_id, secret) =
+            parse_api_key_token(token).ok_or_else(|| ApiError::Auth("Invalid API key".into()))?;
+
+        let api_key = self
+            .get_api_key(key_id)
+            .await?
+            .ok_or_else(|| ApiError::Auth("Invalid API key".into()))?;
+
+        if crypto::hash_token(secret) != api_key.secret_hash {
+            return Err(ApiError::Auth("Invalid API key".into()));
+        }
+
+        Ok(api_key)
+    }
+}
+
+/// API keys are for automation inside a guild, not for managing keys, so a
+/// leaked key can't mint, list or revoke others.
+fn reject_api_key(user: &AuthenticatedUser) -> Result<(), ApiError> {
+    if user.api_key.is_some() {
+        return Err(ApiError::Forbidden(
+            "API keys can't be used to manage API keys".into(),
+        ));
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CreateApiKeyRequest {
+    pub name: String,
+    pub permissions: Permission,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CreateApiKeyResponse {
+    #[serde(flatten)]
+    pub key: ApiKeyInfo,
+    /// Plaintext key. Only returned once, at creation.
+    pub token: String,
+}
+
+/// `GET /api/guilds/{id}/api-keys` - list API keys for a guild.
+#[get("/api/guilds/{id}/api-keys")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn list_api_keys(
+    state: web::Data<State>,
+    path: web::Path<String>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Vec<ApiKeyInfo>>, ApiError> {
+    let guild_id = Id::from_str(&path.into_inner())
+        .map_err(|_| ApiError::ParseError("Invalid guild ID".into()))?;
+    reject_api_key(&user)?;
+
+    state
+        .require_guild_permission(&user, &guild_id, Permission::CONFIG_EDIT)
+        .await?;
+
+    let keys = state.get_guild_api_keys(&guild_id).await?;
+    Ok(web::Json(keys.into_iter().map(ApiKeyInfo::from).collect()))
+}
+
+/// `POST /api/guilds/{id}/api-keys` - create an API key scoped to this guild.
+///
+/// The key can never hold permissions its creator doesn't have.
+#[post("/api/guilds/{id}/api-keys")]
+#[instrument(skip(state, user, body), fields(user_id = %user.user_id))]
+pub async fn create_api_key(
+    state: web::Data<State>,
+    path: web::Path<String>,
+    body: web::Json<CreateApiKeyRequest>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<CreateApiKeyResponse>, ApiError> {
+    let guild_id = Id::from_str(&path.into_inner())
+        .map_err(|_| ApiError::ParseError("Invalid guild ID".into()))?;
+
+    reject_api_key(&user)?;
+
+    let name = body.name.trim();
+    if name.is_empty() || name.len() > 64 {
+        return Err(ApiError::BadRequest(
+            "name must be between 1 and 64 characters".into(),
+        ));
+    }
+
+    let (guild, config) = state
+        .require_guild_permission(&user, &guild_id, Permission::CONFIG_EDIT)
+        .await?;
+
+    let held = state
+        .effective_permissions(&config, &guild, &user.user_id)
+        .await?;
+    if !held.contains(body.permissions) {
+        return Err(ApiError::Forbidden(
+            "Cannot grant an API key permissions you don't hold".into(),
+        ));
+    }
+
+    let (key, token) = state
+        .create_api_key(&guild_id, &user.user_id, name, body.permissions)
+        .await?;
+
+    Ok(web::Json(CreateApiKeyResponse {
+        key: key.into(),
+        token,
+    }))
+}
+
+/// `DELETE /api/guilds/{id}/api-keys/{key_id}` - revoke an API key.
+#[delete("/api/guilds/{id}/api-keys/{key_id}")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn revoke_api_key(
+    state: web::Data<State>,
+    path: web::Path<(String, String)>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<bool>, ApiError> {
+    let (guild_id_str, key_id) = path.into_inner();
+    let guild_id =
+        Id::from_str(&guild_id_str).map_err(|_| ApiError::ParseError("Invalid guild ID".into()))?;
+    reject_api_key(&user)?;
+
+    state
+        .require_guild_permission(&user, &guild_id, Permission::CONFIG_EDIT)
+        .await?;
+
+    let revoked = state.revoke_api_key(&guild_id, &key_id).await?;
+    if !revoked {
+        return Err(ApiError::NotFound("API key not found".into()));
+    }
+
+    Ok(web::Json(revoked))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn api_key() -> ApiKey {
+        ApiKey {
+            id: "0123abcd".into(),
+            name: "deploy bot".into(),
+            guild_id: Id::from_str("111").unwrap(),
+            created_by: Id::from_str("222").unwrap(),
+            permissions: Permission::CONFIG_VIEW | Permission::INFRACTION_VIEW,
+            created_at: 1_700_000_000,
+            secret_hash: crypto::hash_token("secret"),
+        }
+    }
+
+    #[test]
+    fn parses_api_key_tokens() {
+        assert_eq!(
+            parse_api_key_token("bm_0123abcd.s3cr3t"),
+            Some(("0123abcd", "s3cr3t"))
+        );
+        // Everything after the first dot is the secret.
+        assert_eq!(parse_api_key_token("bm_id.a.b"), Some(("id", "a.b")));
+    }
+
+    #[test]
+    fn rejects_malformed_api_key_tokens() {
+        for token in [
+            "",
+            "bm_",
+            "bm_id",
+            "bm_.secret",
+            "bm_id.",
+            "xx_id.secret",
+            "id.secret",
+        ] {
+            assert_eq!(parse_api_key_token(token), None, "{token}");
+        }
+    }
+
+    #[test]
+    fn stored_api_keys_round_trip() {
+        let key = api_key();
+        let parsed = parse_api_key(&serde_json::to_string(&key).unwrap()).unwrap();
+
+        assert_eq!(parsed.id, key.id);
+        assert_eq!(parsed.guild_id, key.guild_id);
+        assert_eq!(parsed.permissions, key.permissions);
+        assert_eq!(parsed.secret_hash, key.secret_hash);
+        assert!(parse_api_key("{}").is_err());
+    }
+
+    #[test]
+    fn api_key_info_hides_the_secret_hash() {
+        let info = serde_json::to_value(ApiKeyInfo::from(api_key())).unwrap();
+
+        assert!(info.get("secret_hash").is_none());
+        assert_eq!(info["name"], "deploy bot");
+    }
+
+    #[test]
+    fn scope_carries_guild_and_permissions() {
+        let key = api_key();
+        let scope = ApiKeyScope::from(&key);
+
+        assert_eq!(scope.key_id, key.id);
+        assert_eq!(scope.guild_id, key.guild_id);
+        assert_eq!(scope.permissions, key.permissions);
+    }
+}
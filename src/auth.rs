@@ -1,37 +1,142 @@
 use crate::error::ApiError;
-use actix_web::{dev::Payload, get, web, FromRequest, HttpRequest};
-use bm_lib::discord::Id;
+use actix_web::{
+    dev::Payload, get, post, web, FromRequest, HttpMessage, HttpRequest, HttpResponse,
+};
+use bm_lib::{discord::Id, permissions::Permission};
 use futures::Future;
-use jsonwebtoken::{decode, DecodingKey, Validation};
+use jsonwebtoken::jwk::JwkSet;
 use serde::{Deserialize, Serialize};
 use std::pin::Pin;
 use tracing::instrument;
 
 use crate::{
+    api_keys::{ApiKeyScope, API_KEY_PREFIX},
+    cookies, crypto,
     discord::DiscordUser,
-    jwt::{self, Claims},
+    jwt,
+    sessions::{DiscordCredentials, Session, SessionStatus},
+    staff::StaffAccessMarker,
     State,
 };
 
 #[derive(Debug, Deserialize)]
 struct OAuthParams {
     pub code: String,
+    pub state: String,
+    pub redirect_uri: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AuthorizeParams {
     pub redirect_uri: Option<String>,
 }
 
+#[derive(Debug, Serialize)]
+struct AuthorizeResponse {
+    /// Discord consent URL to send the user to.
+    pub url: String,
+    pub state: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RefreshRequest {
+    pub refresh_token: String,
+}
+
 #[derive(Debug, Serialize)]
 struct AuthResponse {
+    /// Short-lived access JWT.
+    pub token: String,
+    /// Single-use refresh token; exchange at `/api/oauth/refresh`.
+    pub refresh_token: String,
+    /// Access token lifetime in seconds.
+    pub expires_in: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct IntrospectRequest {
     pub token: String,
+    /// Compute the token owner's permissions in this guild.
+    pub guild_id: Option<String>,
+}
+
+/// Token introspection result. Tokens that fail verification only carry
+/// `active: false`. Verified tokens whose session was revoked or expired are
+/// inactive too, but still report `sub`, `exp` and `session_status` so the
+/// caller can tell why; `permissions` is only computed for active tokens.
+#[derive(Debug, Default, Serialize)]
+struct IntrospectResponse {
+    /// Whether the token is valid and its session is still active.
+    pub active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exp: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub session_status: Option<SessionStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub permissions: Option<Permission>,
+}
+
+/// Returned instead of [`AuthResponse`] in cookie mode; the tokens are only
+/// ever sent as HttpOnly cookies.
+#[derive(Debug, Serialize)]
+struct CookieAuthResponse {
+    /// Access token lifetime in seconds.
+    pub expires_in: u64,
+}
+
+impl AuthResponse {
+    fn issue(state: &State, session: &Session, refresh_token: String) -> Result<Self, ApiError> {
+        let token = jwt::create_token(session, state.access_token_ttl, &state.jwt_keys)
+            .map_err(|e| ApiError::Internal(format!("Failed to create token: {e}")))?;
+
+        Ok(Self {
+            token,
+            refresh_token,
+            expires_in: state.access_token_ttl.as_secs(),
+        })
+    }
+
+    /// Hand a new token pair to the client, as cookies when cookie mode is on
+    /// and in the body otherwise.
+    fn respond(
+        state: &State,
+        session: &Session,
+        refresh_token: String,
+    ) -> Result<HttpResponse, ApiError> {
+        let issued = Self::issue(state, session, refresh_token)?;
+
+        if !state.cookie_auth {
+            return Ok(HttpResponse::Ok().json(issued));
+        }
+
+        let mut response = HttpResponse::Ok();
+        cookies::set_auth_cookies(&mut response, state, issued.token, issued.refresh_token);
+        Ok(response.json(CookieAuthResponse {
+            expires_in: issued.expires_in,
+        }))
+    }
 }
 
 #[derive(Debug)]
 pub struct AuthenticatedUser {
     pub user_id: Id,
-    pub discord_token: String,
-    pub discord_refresh: String,
-    pub discord_token_type: String,
-    pub discord_scope: String,
-    pub discord_expires_in: u64,
+    /// Set when authenticated with a dashboard JWT.
+    pub session_id: Option<String>,
+    /// Set when authenticated with an API key; limits what the user can do.
+    pub api_key: Option<ApiKeyScope>,
+    /// Flagged when a permission check passes only through staff access.
+    pub staff_access: StaffAccessMarker,
+}
+
+impl AuthenticatedUser {
+    /// The dashboard session behind this request. API keys have none.
+    pub fn session_id(&self) -> Result<&str, ApiError> {
+        self.session_id
+            .as_deref()
+            .ok_or_else(|| ApiError::Forbidden("This endpoint requires a user session".into()))
+    }
 }
 
 impl FromRequest for AuthenticatedUser {
@@ -42,56 +147,142 @@ impl FromRequest for AuthenticatedUser {
         let req = req.clone();
 
         Box::pin(async move {
-            let auth_header = req
-                .headers()
-                .get("Authorization")
-                .ok_or_else(|| ApiError::Auth("Missing Authorization header".to_string()))?
-                .to_str()
-                .map_err(|_| ApiError::Auth("Invalid Authorization header".to_string()))?;
-
-            if !auth_header.starts_with("Bearer ") {
-                return Err(ApiError::Auth("Invalid Authorization header".to_string()));
-            }
-
-            let token = auth_header.trim_start_matches("Bearer ");
             let state = req
                 .app_data::<web::Data<State>>()
                 .ok_or_else(|| ApiError::Internal("App state not configured".to_string()))?;
 
-            let claims = decode::<Claims>(
-                token,
-                &DecodingKey::from_secret(state.jwt_secret.as_bytes()),
-                &Validation::default(),
-            )
-            .map_err(|_| ApiError::Auth("Invalid token".to_string()))?;
+            let token = match req.headers().get("Authorization") {
+                Some(auth_header) => {
+                    let auth_header = auth_header
+                        .to_str()
+                        .map_err(|_| ApiError::Auth("Invalid Authorization header".to_string()))?;
+
+                    if !auth_header.starts_with("Bearer ") {
+                        return Err(ApiError::Auth("Invalid Authorization header".to_string()));
+                    }
+
+                    auth_header.trim_start_matches("Bearer ").to_string()
+                }
+                // Browsers attach cookies to cross-site requests too, so
+                // cookie-authenticated requests must also pass the CSRF check.
+                None if state.cookie_auth => {
+                    let cookie = req.cookie(cookies::ACCESS_COOKIE).ok_or_else(|| {
+                        ApiError::Auth("Missing Authorization header".to_string())
+                    })?;
+                    cookies::verify_csrf(&req)?;
+                    cookie.value().to_string()
+                }
+                None => return Err(ApiError::Auth("Missing Authorization header".to_string())),
+            };
+            let token = token.as_str();
+
+            let staff_access = req
+                .extensions()
+                .get::<StaffAccessMarker>()
+                .cloned()
+                .unwrap_or_default();
+
+            if token.starts_with(API_KEY_PREFIX) {
+                let api_key = state.authenticate_api_key(token).await?;
+                return Ok(AuthenticatedUser {
+                    user_id: api_key.created_by,
+                    session_id: None,
+                    api_key: Some(ApiKeyScope::from(&api_key)),
+                    staff_access,
+                });
+            }
+
+            let claims = state
+                .jwt_keys
+                .verify(token)
+                .map_err(|_| ApiError::Auth("Invalid token".to_string()))?;
 
             let user_id = claims
-                .claims
                 .sub
                 .parse()
                 .map_err(|_| ApiError::Auth("Invalid user ID".to_string()))?;
 
+            let session = state.check_session(&claims.jti, &user_id).await?;
+
             Ok(AuthenticatedUser {
                 user_id,
-                discord_token: claims.claims.discord_token,
-                discord_refresh: claims.claims.discord_refresh,
-                discord_token_type: claims.claims.discord_token_type,
-                discord_scope: claims.claims.discord_scope,
-                discord_expires_in: claims.claims.discord_expires_in,
+                session_id: Some(session.id),
+                api_key: None,
+                staff_access,
             })
         })
     }
 }
 
+/// The redirect URI a login returns to: `requested` if it is on the
+/// allow-list, or the first allowed URI by default. Only exact matches count.
+fn allowed_redirect_uri<'a>(
+    allowed: &'a [String],
+    requested: Option<&'a str>,
+) -> Result<&'a str, ApiError> {
+    let redirect_uri = requested.unwrap_or(&allowed[0]);
+    if !allowed.iter().any(|allowed| allowed == redirect_uri) {
+        return Err(ApiError::BadRequest("redirect_uri is not allowed".into()));
+    }
+
+    Ok(redirect_uri)
+}
+
+/// `GET /api/oauth/discord/authorize` - start a Discord login.
+///
+/// Returns the Discord consent URL with a single-use `state` and PKCE challenge.
+/// The `redirect_uri` must be on the configured allow-list.
+#[get("/api/oauth/discord/authorize")]
+#[instrument(skip(state, params))]
+pub async fn oauth_discord_authorize(
+    state: web::Data<State>,
+    params: web::Query<AuthorizeParams>,
+) -> Result<web::Json<AuthorizeResponse>, ApiError> {
+    let redirect_uri = allowed_redirect_uri(
+        &state.oauth_allowed_redirect_uris,
+        params.redirect_uri.as_deref(),
+    )?;
+
+    let (oauth_state, pending) = state.begin_login(redirect_uri).await?;
+    // The S256 PKCE challenge is the unpadded base64url SHA-256 of the verifier.
+    let code_challenge = crypto::hash_token(&pending.code_verifier);
+    let url = state
+        .rest
+        .authorize_url(&oauth_state, &code_challenge, Some(redirect_uri));
+
+    Ok(web::Json(AuthorizeResponse {
+        url,
+        state: oauth_state,
+    }))
+}
+
+/// `GET /api/oauth/discord` - complete a login started at
+/// `/api/oauth/discord/authorize` and issue API tokens (as cookies in cookie mode).
 #[get("/api/oauth/discord")]
 #[instrument(skip(state, params))]
 pub async fn oauth_discord(
     state: web::Data<State>,
     params: web::Query<OAuthParams>,
-) -> Result<web::Json<AuthResponse>, ApiError> {
+) -> Result<HttpResponse, ApiError> {
+    let pending = state.complete_login(&params.state).await?;
+
+    if params
+        .redirect_uri
+        .as_ref()
+        .is_some_and(|uri| *uri != pending.redirect_uri)
+    {
+        return Err(ApiError::BadRequest(
+            "redirect_uri does not match the one used to start the login".into(),
+        ));
+    }
+
     let oauth_response = state
         .rest
-        .oauth_token(params.code.clone(), params.redirect_uri.clone())
+        .oauth_token(
+            params.code.clone(),
+            pending.code_verifier,
+            Some(pending.redirect_uri),
+        )
         .await?;
 
     let me = state.rest.get_self(&oauth_response.access_token).await?;
@@ -104,18 +295,12 @@ pub async fn oauth_discord(
     // Cache the user profile so /api/me doesn't need to hit Discord.
     state.set_user(&id, &me).await?;
 
-    let token = jwt::create_token(
-        &id.to_string(),
-        &oauth_response.access_token,
-        &oauth_response.refresh_token,
-        &oauth_response.token_type,
-        &oauth_response.scope,
-        oauth_response.expires_in,
-        &state.jwt_secret,
-    )
-    .map_err(|e| ApiError::Internal(format!("Failed to create token: {e}")))?;
+    // Discord credentials are sealed into the session record, never the JWT.
+    let (session, refresh_token) = state
+        .create_session(&id, &DiscordCredentials::from(oauth_response))
+        .await?;
 
-    Ok(web::Json(AuthResponse { token }))
+    AuthResponse::respond(&state, &session, refresh_token)
 }
 
 /// `GET /api/me` - return the authenticated user's profile.
@@ -130,9 +315,10 @@ pub async fn get_me(
         return Ok(web::Json(cached));
     }
 
+    let credentials = state.discord_credentials(&user).await?;
     let me = state
         .rest
-        .get_self(&user.discord_token)
+        .get_self(&credentials.access_token)
         .await
         .map_err(ApiError::Discord)?;
 
@@ -140,23 +326,215 @@ pub async fn get_me(
     Ok(web::Json(me))
 }
 
-#[get("/api/oauth/refresh")]
-#[instrument(skip(state), fields(user_id = %user.user_id))]
-pub async fn refresh_token(
+/// `POST /api/oauth/refresh` - exchange a refresh token for a new access token.
+///
+/// Refresh tokens are single-use: each call returns a new one and invalidates
+/// the old one. Presenting an already-used token revokes the whole session.
+/// In cookie mode the token may come from the refresh cookie instead of the body.
+#[post("/api/oauth/refresh")]
+#[instrument(skip(state, req, body))]
+pub async fn refresh(
+    state: web::Data<State>,
+    req: HttpRequest,
+    body: Option<web::Json<RefreshRequest>>,
+) -> Result<HttpResponse, ApiError> {
+    let presented = match body {
+        Some(body) => body.into_inner().refresh_token,
+        None if state.cookie_auth => {
+            let cookie = req
+                .cookie(cookies::REFRESH_COOKIE)
+                .ok_or_else(|| ApiError::Auth("Missing refresh token".into()))?;
+            cookies::verify_csrf(&req)?;
+            cookie.value().to_string()
+        }
+        None => return Err(ApiError::BadRequest("Missing refresh token".into())),
+    };
+
+    let (session, refresh_token) = state.redeem_refresh_token(&presented).await?;
+
+    // Rotate the Discord tokens too if they're about to expire, so the new JWT
+    // is backed by a usable Discord grant. The refresh token has already been
+    // rotated, so only a dead session or grant fails the request; on transient
+    // errors the client keeps the new token and the next refresh retries.
+    let session = match state.refresh_discord_credentials(session.clone()).await {
+        Ok(session) => session,
+        Err(err @ (ApiError::ReauthRequired(_) | ApiError::Auth(_))) => return Err(err),
+        Err(err) => {
+            tracing::warn!(session_id = %session.id, error = %err, "Failed to refresh Discord credentials");
+            session
+        }
+    };
+
+    AuthResponse::respond(&state, &session, refresh_token)
+}
+
+/// Success body for logout, clearing the auth cookies in cookie mode.
+fn logged_out(state: &State) -> HttpResponse {
+    let mut response = HttpResponse::Ok();
+    if state.cookie_auth {
+        cookies::clear_auth_cookies(&mut response);
+    }
+    response.json(serde_json::json!({ "success": true }))
+}
+
+/// `POST /api/oauth/logout` - revoke the session behind the current token.
+#[post("/api/oauth/logout")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn logout(
+    state: web::Data<State>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let session = state
+        .check_session(user.session_id()?, &user.user_id)
+        .await?;
+    state.revoke_session(&session).await?;
+
+    Ok(logged_out(&state))
+}
+
+/// `POST /api/oauth/logout/all` - revoke every session the user holds,
+/// logging them out on all devices.
+#[post("/api/oauth/logout/all")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn logout_all(
     state: web::Data<State>,
     user: AuthenticatedUser,
-) -> Result<web::Json<AuthResponse>, ApiError> {
-    // Local JWT refresh: no Discord token refresh or bot API calls required.
-    let token = jwt::create_token(
-        &user.user_id.to_string(),
-        &user.discord_token,
-        &user.discord_refresh,
-        &user.discord_token_type,
-        &user.discord_scope,
-        user.discord_expires_in,
-        &state.jwt_secret,
-    )
-    .map_err(|e| ApiError::Internal(format!("Failed to create token: {e}")))?;
-
-    Ok(web::Json(AuthResponse { token }))
+) -> Result<HttpResponse, ApiError> {
+    user.session_id()?;
+    state.revoke_all_sessions(&user.user_id).await?;
+
+    Ok(logged_out(&state))
+}
+
+/// Check the `Authorization: Bearer` header against the configured service tokens.
+fn require_service_token(req: &HttpRequest, state: &State) -> Result<(), ApiError> {
+    let token = req
+        .headers()
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or_else(|| ApiError::Auth("Missing service token".into()))?;
+
+    let hash = crypto::hash_token(token);
+    if !state.service_token_hashes.contains(&hash) {
+        return Err(ApiError::Auth("Invalid service token".into()));
+    }
+
+    Ok(())
+}
+
+/// `POST /api/oauth/introspect` - check a dashboard access token on behalf of
+/// another Black Mesa service, authenticated with a service token.
+///
+/// Invalid or expired tokens aren't an error; they report `active: false`.
+/// See [`IntrospectResponse`] for which fields inactive tokens carry.
+#[post("/api/oauth/introspect")]
+#[instrument(skip(state, req, body))]
+pub async fn introspect(
+    state: web::Data<State>,
+    req: HttpRequest,
+    body: web::Json<IntrospectRequest>,
+) -> Result<web::Json<IntrospectResponse>, ApiError> {
+    require_service_token(&req, &state)?;
+
+    let Ok(claims) = state.jwt_keys.verify(&body.token) else {
+        return Ok(web::Json(IntrospectResponse::default()));
+    };
+    let Ok(user_id) = claims.sub.parse::<Id>() else {
+        return Ok(web::Json(IntrospectResponse::default()));
+    };
+
+    // Sessions disappear from the cache once they expire.
+    let session_status = match state.get_session(&claims.jti).await? {
+        Some(session) if session.user_id == user_id => state.session_status(&session).await?,
+        _ => SessionStatus::Expired,
+    };
+    let active = session_status == SessionStatus::Active;
+
+    let permissions = match &body.guild_id {
+        Some(guild_id) if active => {
+            let guild_id = Id::from_str(guild_id)
+                .map_err(|_| ApiError::ParseError("Invalid guild ID".into()))?;
+            let guild = state
+                .get_guild(&guild_id)
+                .await?
+                .ok_or_else(|| ApiError::NotFound("Guild not found".into()))?;
+            let config = state
+                .get_config(&guild_id)
+                .await?
+                .ok_or_else(|| ApiError::NotFound("Config not found".into()))?;
+
+            Some(
+                state
+                    .effective_permissions(&config, &guild, &user_id)
+                    .await?,
+            )
+        }
+        _ => None,
+    };
+
+    Ok(web::Json(IntrospectResponse {
+        active,
+        sub: Some(claims.sub),
+        exp: Some(claims.exp),
+        session_status: Some(session_status),
+        permissions,
+    }))
+}
+
+/// `GET /.well-known/jwks.json` - public keys that verify API tokens, for
+/// internal services. Empty when tokens are signed with a shared secret.
+#[get("/.well-known/jwks.json")]
+pub async fn jwks(state: web::Data<State>) -> web::Json<JwkSet> {
+    web::Json(state.jwt_keys.jwks().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn allowed() -> Vec<String> {
+        vec![
+            "https://dashboard.example.com/callback".into(),
+            "http://localhost:3000/callback".into(),
+        ]
+    }
+
+    #[test]
+    fn defaults_to_the_first_allowed_redirect() {
+        assert_eq!(
+            allowed_redirect_uri(&allowed(), None).unwrap(),
+            "https://dashboard.example.com/callback"
+        );
+    }
+
+    #[test]
+    fn accepts_allowed_redirects() {
+        let allowed = allowed();
+        assert_eq!(
+            allowed_redirect_uri(&allowed, Some("http://localhost:3000/callback")).unwrap(),
+            "http://localhost:3000/callback"
+        );
+    }
+
+    #[test]
+    fn rejects_redirects_that_only_look_allowed() {
+        let allowed = allowed();
+        for uri in [
+            "https://evil.example.com/callback",
+            "https://dashboard.example.com/callback/",
+            "https://dashboard.example.com/callback?next=https://evil.example.com",
+            "https://dashboard.example.com.evil.example.com/callback",
+            "HTTPS://DASHBOARD.EXAMPLE.COM/CALLBACK",
+            "",
+        ] {
+            assert!(
+                matches!(
+                    allowed_redirect_uri(&allowed, Some(uri)),
+                    Err(ApiError::BadRequest(_))
+                ),
+                "{uri} was allowed"
+            );
+        }
+    }
 }
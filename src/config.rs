@@ -18,7 +18,34 @@ pub struct Settings {
     pub discord_client_id: String,
     pub discord_client_secret: String,
     pub discord_redirect_uri: String,
-    pub jwt_secret: String,
+    /// Redirect URIs the OAuth flow may send users back to. The first entry is
+    /// always `discord_redirect_uri`, used when the client doesn't pick one.
+    pub oauth_allowed_redirect_uris: Vec<String>,
+    /// `HS256` (shared `jwt_secret`), `RS256` or `EdDSA`.
+    pub jwt_algorithm: String,
+    pub jwt_secret: Option<String>,
+    /// PEM private key used to sign tokens with an asymmetric algorithm.
+    pub jwt_private_key_file: Option<String>,
+    /// `kid` of the signing key; must be present in `jwt_jwks_file`.
+    pub jwt_key_id: Option<String>,
+    /// JWKS of every public key tokens may be verified with.
+    pub jwt_jwks_file: Option<String>,
+    /// Lifetime of API access tokens (JWTs), in seconds.
+    pub access_token_ttl: u64,
+    /// Lifetime of a session's refresh token, in seconds. Each refresh
+    /// rotates the token and restarts this window.
+    pub refresh_token_ttl: u64,
+    /// Base64-encoded 32-byte key used to encrypt Discord tokens at rest.
+    pub session_encryption_key: String,
+    /// Issue tokens as HttpOnly cookies instead of in response bodies, and
+    /// accept them from cookies with CSRF double-submit protection.
+    pub cookie_auth: bool,
+    /// Bot staff with full access to every guild.
+    pub staff_user_ids: Vec<String>,
+    /// Bot staff who may view, but not change, any guild.
+    pub staff_read_only_user_ids: Vec<String>,
+    /// Credentials internal services use to call `/api/oauth/introspect`.
+    pub service_tokens: Vec<String>,
 }
 
 impl Settings {
@@ -31,6 +58,32 @@ impl Settings {
             .and_then(|value| value.parse::<u16>().ok())
             .unwrap_or(8080);
 
+        let access_token_ttl = env::var("ACCESS_TOKEN_TTL")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(15 * 60);
+
+        let refresh_token_ttl = env::var("REFRESH_TOKEN_TTL")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(30 * 24 * 60 * 60);
+
+        let cookie_auth = env::var("COOKIE_AUTH")
+            .ok()
+            .is_some_and(|value| matches!(value.as_str(), "1" | "true"));
+
+        let discord_redirect_uri = required("DISCORD_REDIRECT_URI")?;
+        let mut oauth_allowed_redirect_uris = vec![discord_redirect_uri.clone()];
+        for uri in env::var("OAUTH_ALLOWED_REDIRECT_URIS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+        {
+            if !uri.is_empty() && !oauth_allowed_redirect_uris.iter().any(|u| u == uri) {
+                oauth_allowed_redirect_uris.push(uri.to_string());
+            }
+        }
+
         Ok(Self {
             api_host: env::var("API_HOST").unwrap_or_else(|_| "0.0.0.0".to_string()),
             api_port,
@@ -46,12 +99,35 @@ impl Settings {
             discord_bot_token: required("DISCORD_BOT_TOKEN")?,
             discord_client_id: required("DISCORD_CLIENT_ID")?,
             discord_client_secret: required("DISCORD_CLIENT_SECRET")?,
-            discord_redirect_uri: required("DISCORD_REDIRECT_URI")?,
-            jwt_secret: required("JWT_SECRET")?,
+            discord_redirect_uri,
+            oauth_allowed_redirect_uris,
+            jwt_algorithm: env::var("JWT_ALGORITHM").unwrap_or_else(|_| "HS256".to_string()),
+            jwt_secret: env::var("JWT_SECRET").ok(),
+            jwt_private_key_file: env::var("JWT_PRIVATE_KEY_FILE").ok(),
+            jwt_key_id: env::var("JWT_KEY_ID").ok(),
+            jwt_jwks_file: env::var("JWT_JWKS_FILE").ok(),
+            access_token_ttl,
+            refresh_token_ttl,
+            session_encryption_key: required("SESSION_ENCRYPTION_KEY")?,
+            cookie_auth,
+            staff_user_ids: list("STAFF_USER_IDS"),
+            staff_read_only_user_ids: list("STAFF_READ_ONLY_USER_IDS"),
+            service_tokens: list("SERVICE_TOKENS"),
         })
     }
 }
 
+/// Comma-separated list variable; empty when unset.
+fn list(key: &str) -> Vec<String> {
+    env::var(key)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
 fn required(key: &str) -> Result<String> {
     env::var(key).map_err(|_| {
         Error::new(
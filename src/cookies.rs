@@ -0,0 +1,89 @@
+use actix_web::{
+    cookie::{time, Cookie, SameSite},
+    http::Method,
+    HttpRequest, HttpResponseBuilder,
+};
+
+use crate::{crypto, error::ApiError, State};
+
+/// HttpOnly cookie carrying the access JWT.
+pub const ACCESS_COOKIE: &str = "bm_access";
+/// HttpOnly cookie carrying the refresh token; only sent to the refresh endpoint.
+pub const REFRESH_COOKIE: &str = "bm_refresh";
+/// Readable cookie the dashboard echoes back in [`CSRF_HEADER`].
+pub const CSRF_COOKIE: &str = "bm_csrf";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+const REFRESH_COOKIE_PATH: &str = "/api/oauth/refresh";
+
+fn build_cookie(
+    name: &'static str,
+    value: String,
+    path: &'static str,
+    http_only: bool,
+) -> Cookie<'static> {
+    Cookie::build(name, value)
+        .path(path)
+        .secure(true)
+        .http_only(http_only)
+        .same_site(SameSite::Strict)
+        .finish()
+}
+
+/// Attach the access, refresh and CSRF cookies for a freshly issued token pair.
+pub fn set_auth_cookies(
+    response: &mut HttpResponseBuilder,
+    state: &State,
+    access_token: String,
+    refresh_token: String,
+) {
+    let access_max_age = time::Duration::seconds(state.access_token_ttl.as_secs() as i64);
+    let refresh_max_age = time::Duration::seconds(state.refresh_token_ttl.as_secs() as i64);
+
+    let mut access = build_cookie(ACCESS_COOKIE, access_token, "/", true);
+    access.set_max_age(access_max_age);
+    let mut refresh = build_cookie(REFRESH_COOKIE, refresh_token, REFRESH_COOKIE_PATH, true);
+    refresh.set_max_age(refresh_max_age);
+    let mut csrf = build_cookie(CSRF_COOKIE, crypto::random_token(32), "/", false);
+    csrf.set_max_age(refresh_max_age);
+
+    response.cookie(access).cookie(refresh).cookie(csrf);
+}
+
+/// Expire every auth cookie.
+pub fn clear_auth_cookies(response: &mut HttpResponseBuilder) {
+    for (name, path, http_only) in [
+        (ACCESS_COOKIE, "/", true),
+        (REFRESH_COOKIE, REFRESH_COOKIE_PATH, true),
+        (CSRF_COOKIE, "/", false),
+    ] {
+        let mut cookie = build_cookie(name, String::new(), path, http_only);
+        cookie.make_removal();
+        response.cookie(cookie);
+    }
+}
+
+/// Double-submit CSRF check for cookie-authenticated requests: unsafe methods
+/// must echo the CSRF cookie in the [`CSRF_HEADER`] header. A cross-site page
+/// can make the browser send the cookie but can't read it to set the header.
+pub fn verify_csrf(req: &HttpRequest) -> Result<(), ApiError> {
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Ok(());
+    }
+
+    let cookie = req
+        .cookie(CSRF_COOKIE)
+        .ok_or_else(|| ApiError::Forbidden("Missing CSRF cookie".into()))?;
+    let header = req
+        .headers()
+        .get(CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .ok_or_else(|| ApiError::Forbidden("Missing CSRF token".into()))?;
+
+    // Compare digests so the check doesn't leak how much of the token matched.
+    if crypto::hash_token(cookie.value()) != crypto::hash_token(header) {
+        return Err(ApiError::Forbidden("Invalid CSRF token".into()));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,587 @@
+use std::collections::HashMap;
+
+use actix_web::{http::header, post, web, HttpRequest, HttpResponse};
+use bm_lib::{
+    discord::{Channel, Guild, Id},
+    model::{
+        automod::{Automod, AutomodSettings, PermissionOverride},
+        logging::LogConfig,
+        Config, Group,
+    },
+    permissions::Permission,
+};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{
+    api::{config_etag, require_if_match},
+    auth::AuthenticatedUser,
+    diff::{diff_configs, pointer_push},
+    error::ApiError,
+    validation, State,
+};
+
+/// A part of a guild's config that can be copied to another guild.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CopySection {
+    Automod,
+    PermissionGroups,
+    Aliases,
+    Logging,
+}
+
+impl CopySection {
+    pub const ALL: [CopySection; 4] = [
+        CopySection::Automod,
+        CopySection::PermissionGroups,
+        CopySection::Aliases,
+        CopySection::Logging,
+    ];
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IdKind {
+    Role,
+    Channel,
+}
+
+/// A role or channel ID in a copied section with no same-named counterpart in
+/// the target guild. It is left out of the copied section.
+#[derive(Debug, Clone, Serialize)]
+pub struct UnmappedId {
+    /// JSON Pointer to where the ID appeared, e.g. `/permission_groups/0/roles/1`.
+    pub path: String,
+    pub kind: IdKind,
+    pub id: String,
+    pub name: String,
+    /// Why it couldn't be mapped: no target role or channel has the name,
+    /// several do, or the source guild has no such role or channel.
+    pub reason: String,
+}
+
+struct MappedId {
+    name: String,
+    target: Result<Id, &'static str>,
+}
+
+/// Source guild roles and channels, with the same-named target guild role or
+/// channel.
+struct IdMap {
+    roles: HashMap<Id, MappedId>,
+    channels: HashMap<Id, MappedId>,
+}
+
+impl IdMap {
+    fn new(
+        source: &Guild,
+        target: &Guild,
+        source_channels: &[Channel],
+        target_channels: &[Channel],
+    ) -> Self {
+        let mut roles = HashMap::new();
+        for role in &source.roles {
+            let matches: Vec<Id> = target
+                .roles
+                .iter()
+                .filter(|r| r.name == role.name)
+                .map(|r| r.id)
+                .collect();
+            roles.insert(
+                role.id,
+                MappedId {
+                    name: role.name.to_string(),
+                    target: single(&matches),
+                },
+            );
+        }
+
+        let target_names: Vec<(Id, Option<String>)> = target_channels
+            .iter()
+            .map(|c| (c.id, channel_name(c)))
+            .collect();
+        let mut channels = HashMap::new();
+        for channel in source_channels {
+            let name = channel_name(channel);
+            let matches: Vec<Id> = target_names
+                .iter()
+                .filter(|(_, n)| name.is_some() && *n == name)
+                .map(|(id, _)| *id)
+                .collect();
+            channels.insert(
+                channel.id,
+                MappedId {
+                    name: name.unwrap_or_default(),
+                    target: single(&matches),
+                },
+            );
+        }
+
+        Self { roles, channels }
+    }
+
+    /// Map a source role or channel ID to the target guild's. IDs without a
+    /// target are added to `unmapped` and return `None`.
+    fn map(&self, kind: IdKind, id: Id, path: &str, unmapped: &mut Vec<UnmappedId>) -> Option<Id> {
+        let ids = match kind {
+            IdKind::Role => &self.roles,
+            IdKind::Channel => &self.channels,
+        };
+        let (name, reason) = match ids.get(&id) {
+            Some(MappedId {
+                target: Ok(target), ..
+            }) => return Some(*target),
+            Some(MappedId {
+                name,
+                target: Err(reason),
+            }) => (name.clone(), *reason),
+            None => (String::new(), "not a role or channel of the source guild"),
+        };
+
+        unmapped.push(UnmappedId {
+            path: path.to_string(),
+            kind,
+            id: id.to_string(),
+            name,
+            reason: reason.to_string(),
+        });
+        None
+    }
+
+    /// Map a list of IDs, dropping those without a target.
+    fn map_all(
+        &self,
+        kind: IdKind,
+        ids: &[Id],
+        path: &str,
+        unmapped: &mut Vec<UnmappedId>,
+    ) -> Vec<Id> {
+        ids.iter()
+            .enumerate()
+            .filter_map(|(i, id)| self.map(kind, *id, &format!("{path}/{i}"), unmapped))
+            .collect()
+    }
+
+    fn remap_groups(&self, groups: &mut [Group], unmapped: &mut Vec<UnmappedId>) {
+        for (i, group) in groups.iter_mut().enumerate() {
+            group.roles = self.map_all(
+                IdKind::Role,
+                &group.roles,
+                &format!("/permission_groups/{i}/roles"),
+                unmapped,
+            );
+        }
+    }
+
+    /// Map automod's channel overrides and bypass roles. Overrides for
+    /// channels without a target are dropped.
+    fn remap_automod(&self, automod: &mut Automod, unmapped: &mut Vec<UnmappedId>) {
+        if let Some(global) = &mut automod.global {
+            self.remap_settings(global, "/automod/global", unmapped);
+        }
+
+        let mut overrides: Vec<_> = std::mem::take(&mut automod.channels).into_iter().collect();
+        overrides.sort_by_key(|(channel_id, _)| *channel_id);
+        for (channel_id, mut settings) in overrides {
+            let path = format!("/automod/channels/{channel_id}");
+            if let Some(target) = self.map(IdKind::Channel, channel_id, &path, unmapped) {
+                self.remap_settings(&mut settings, &path, unmapped);
+                automod.channels.insert(target, settings);
+            }
+        }
+    }
+
+    fn remap_settings(
+        &self,
+        settings: &mut AutomodSettings,
+        path: &str,
+        unmapped: &mut Vec<UnmappedId>,
+    ) {
+        if let Some(bypass) = &mut settings.bypass {
+            self.remap_bypass(bypass, &format!("{path}/bypass"), unmapped);
+        }
+
+        let mut censors: Vec<_> = settings
+            .censors
+            .iter_mut()
+            .map(|(kind, censor)| (kind.to_string(), censor))
+            .collect();
+        censors.sort_by(|a, b| a.0.cmp(&b.0));
+        for (kind, censor) in censors {
+            if let Some(bypass) = &mut censor.bypass {
+                self.remap_bypass(bypass, &format!("{path}/censors/{kind}/bypass"), unmapped);
+            }
+        }
+
+        if let Some(bypass) = settings.spam.as_mut().and_then(|spam| spam.bypass.as_mut()) {
+            self.remap_bypass(bypass, &format!("{path}/spam/bypass"), unmapped);
+        }
+    }
+
+    /// Map bypass roles. Users and permission group names carry over as is.
+    fn remap_bypass(
+        &self,
+        bypass: &mut PermissionOverride,
+        path: &str,
+        unmapped: &mut Vec<UnmappedId>,
+    ) {
+        bypass.roles = self.map_all(
+            IdKind::Role,
+            &bypass.roles,
+            &format!("{path}/roles"),
+            unmapped,
+        );
+    }
+}
+
+fn single(matches: &[Id]) -> Result<Id, &'static str> {
+    match matches {
+        [id] => Ok(*id),
+        [] => Err("no role or channel with this name in the target guild"),
+        _ => Err("several roles or channels share this name in the target guild"),
+    }
+}
+
+fn channel_name(channel: &Channel) -> Option<String> {
+    serde_json::to_value(channel)
+        .ok()?
+        .get("name")?
+        .as_str()
+        .map(str::to_string)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CopyRequest {
+    /// Defaults to every section.
+    #[serde(default)]
+    pub sections: Option<Vec<CopySection>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CopyResponse {
+    pub config: Config,
+    pub unmapped: Vec<UnmappedId>,
+}
+
+/// `POST /api/config/{target}/copy-from/{source}` - copy config sections from
+/// another guild, remapping role and channel IDs to the target guild's roles
+/// and channels of the same name.
+///
+/// Copied sections replace the target's. Requires `CONFIG_VIEW` on the source,
+/// `CONFIG_EDIT` on the target and an `If-Match` header with the target
+/// config's ETag.
+#[post("/api/config/{target}/copy-from/{source}")]
+#[instrument(skip(state, user, req, body), fields(user_id = %user.user_id))]
+pub async fn copy_config(
+    state: web::Data<State>,
+    path: web::Path<(String, String)>,
+    req: HttpRequest,
+    body: Option<web::Json<CopyRequest>>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let (target_id, source_id) = path.into_inner();
+    let target_id = Id::from_str(&target_id)
+        .map_err(|_| ApiError::ParseError("Invalid target guild ID".into()))?;
+    let source_id = Id::from_str(&source_id)
+        .map_err(|_| ApiError::ParseError("Invalid source guild ID".into()))?;
+    if target_id == source_id {
+        return Err(ApiError::BadRequest(
+            "Source and target guild must differ".into(),
+        ));
+    }
+
+    let (source_guild, source_config) = state
+        .require_guild_permission(&user, &source_id, Permission::CONFIG_VIEW)
+        .await?;
+    let (target_guild, target_config) = state
+        .require_guild_permission(&user, &target_id, Permission::CONFIG_EDIT)
+        .await?;
+    require_if_match(&req, &target_config)?;
+
+    let sections = body
+        .and_then(|body| body.into_inner().sections)
+        .unwrap_or_else(|| CopySection::ALL.to_vec());
+
+    let source_channels = state.guild_channels(&source_id).await?;
+    let target_channels = state.guild_channels(&target_id).await?;
+    let ids = IdMap::new(
+        &source_guild,
+        &target_guild,
+        &source_channels,
+        &target_channels,
+    );
+    let mut unmapped = Vec::new();
+
+    let mut config = target_config.clone();
+    for section in &sections {
+        match section {
+            CopySection::Automod => {
+                config.automod = source_config.automod.clone();
+                if let Some(automod) = &mut config.automod {
+                    ids.remap_automod(automod, &mut unmapped);
+                }
+            }
+            CopySection::PermissionGroups => {
+                config.permission_groups = source_config.permission_groups.clone();
+                if let Some(groups) = &mut config.permission_groups {
+                    ids.remap_groups(groups, &mut unmapped);
+                }
+            }
+            CopySection::Aliases => {
+                config.command_aliases = source_config.command_aliases.clone();
+            }
+            CopySection::Logging => {}
+        }
+    }
+
+    let logs = if sections.contains(&CopySection::Logging) {
+        let mut logs = Vec::new();
+        for log in state.db.get_log_configs(&source_id).await? {
+            let channel_id = log.channel_id.and_then(|channel| {
+                ids.map(
+                    IdKind::Channel,
+                    channel,
+                    &format!("{}/channel_id", pointer_push("/logging", &log.event)),
+                    &mut unmapped,
+                )
+            });
+            logs.push(LogConfig {
+                id: None,
+                guild_id: target_id,
+                channel_id,
+                ..log
+            });
+        }
+        Some(logs)
+    } else {
+        None
+    };
+
+    let commands = state.get_bot_commands().await?;
+    let mut errors =
+        validation::validate_config(&config, &target_guild, &target_channels, &commands);
+    if let Some(logs) = &logs {
+        errors.extend(validation::validate_log_configs(logs, &target_channels));
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+
+    state
+        .check_escalation(&target_guild, &target_config, &config, &user)
+        .await?;
+
+    let config = if diff_configs(&target_config, &config)?.is_empty() {
+        target_config
+    } else {
+        let reason = format!("Copied from guild {}", source_id);
+        state
+            .update_config(
+                &target_id,
+                &target_config,
+                &config,
+                &user.user_id,
+                Some(&reason),
+            )
+            .await?
+    };
+
+    if let Some(logs) = logs {
+        state.replace_log_configs(&target_id, &logs).await?;
+    }
+
+    Ok(HttpResponse::Ok()
+        .insert_header((header::ETAG, config_etag(&config)?))
+        .json(CopyResponse { config, unmapped }))
+}
+
+#[cfg(test)]
+mod tests {
+    use bm_lib::model::automod::{CensorType, SpamType};
+    use serde_json::json;
+
+    use super::*;
+
+    fn id(n: u64) -> Id {
+        Id::from_str(&n.to_string()).unwrap()
+    }
+
+    fn guild(guild_id: u64, roles: &[(u64, &str)]) -> Guild {
+        let roles: Vec<_> = roles
+            .iter()
+            .map(|(role_id, name)| {
+                json!({ "id": id(*role_id), "name": name, "position": 0, "permissions": 0, "managed": false })
+            })
+            .collect();
+        serde_json::from_value(json!({
+            "id": id(guild_id),
+            "name": "guild",
+            "icon": null,
+            "owner_id": null,
+            "roles": roles,
+            "member_count": null,
+            "approximate_member_count": null,
+        }))
+        .unwrap()
+    }
+
+    fn channels(channels: &[(u64, &str)]) -> Vec<Channel> {
+        channels
+            .iter()
+            .map(|(channel_id, name)| {
+                serde_json::from_value(json!({ "id": id(*channel_id), "name": name, "type": 0 }))
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    /// Source roles 10 (mods), 11 (admins), 12 (helpers) and channels 20
+    /// (general), 21 (logs). The target has one `mods`, two `admins`, no
+    /// `helpers`, a `general` channel and no `logs`.
+    fn id_map() -> IdMap {
+        IdMap::new(
+            &guild(1, &[(10, "mods"), (11, "admins"), (12, "helpers")]),
+            &guild(2, &[(110, "mods"), (111, "admins"), (112, "admins")]),
+            &channels(&[(20, "general"), (21, "logs")]),
+            &channels(&[(120, "general")]),
+        )
+    }
+
+    #[test]
+    fn maps_ids_by_name() {
+        let ids = id_map();
+        let mut unmapped = Vec::new();
+
+        assert_eq!(
+            ids.map(IdKind::Role, id(10), "role", &mut unmapped),
+            Some(id(110))
+        );
+        assert_eq!(
+            ids.map(IdKind::Channel, id(20), "channel", &mut unmapped),
+            Some(id(120))
+        );
+        assert!(unmapped.is_empty());
+    }
+
+    #[test]
+    fn reports_unmapped_ids() {
+        let ids = id_map();
+        let mut unmapped = Vec::new();
+
+        assert_eq!(
+            ids.map_all(
+                IdKind::Role,
+                &[id(11), id(10), id(12), id(99)],
+                "/roles",
+                &mut unmapped
+            ),
+            [id(110)]
+        );
+        // A channel ID isn't looked up as a role.
+        assert_eq!(ids.map(IdKind::Role, id(20), "/role", &mut unmapped), None);
+
+        let report: Vec<_> = unmapped
+            .iter()
+            .map(|u| (u.path.as_str(), u.kind, u.id.as_str(), u.name.as_str()))
+            .collect();
+        assert_eq!(
+            report,
+            [
+                ("/roles/0", IdKind::Role, "11", "admins"),
+                ("/roles/2", IdKind::Role, "12", "helpers"),
+                ("/roles/3", IdKind::Role, "99", ""),
+                ("/role", IdKind::Role, "20", ""),
+            ]
+        );
+        assert_eq!(
+            unmapped[0].reason,
+            "several roles or channels share this name in the target guild"
+        );
+        assert_eq!(
+            unmapped[1].reason,
+            "no role or channel with this name in the target guild"
+        );
+    }
+
+    #[test]
+    fn remaps_only_group_roles() {
+        let ids = id_map();
+        let mut unmapped = Vec::new();
+        let mut groups: Vec<Group> = serde_json::from_value(json!([
+            { "name": "mods", "roles": [id(10), id(21)], "users": [id(10)], "permissions": 10 },
+        ]))
+        .unwrap();
+
+        ids.remap_groups(&mut groups, &mut unmapped);
+
+        assert_eq!(groups[0].roles, [id(110)]);
+        // User IDs and permission bits are never remapped, even if they equal
+        // a role ID.
+        assert_eq!(groups[0].users, [id(10)]);
+        assert_eq!(groups[0].permissions.bits(), 10);
+        assert_eq!(unmapped.len(), 1);
+        assert_eq!(unmapped[0].path, "/permission_groups/0/roles/1");
+    }
+
+    #[test]
+    fn remaps_automod_channels_and_bypass_roles() {
+        let ids = id_map();
+        let mut unmapped = Vec::new();
+        let settings = |name: &str| {
+            json!({
+                "name": name,
+                "enabled": true,
+                "bypass": { "groups": ["mods"], "roles": [id(10)], "users": [id(20)] },
+                "censors": {
+                    "word": {
+                        "enabled": true,
+                        "filters": ["10", "20"],
+                        "bypass": { "roles": [id(12)] },
+                        "action": { "action": "warn", "duration": 0 },
+                    },
+                },
+                "spam": {
+                    "enabled": true,
+                    "filters": { "message": { "interval": 10, "count": 20 } },
+                    "bypass": { "roles": [id(11)] },
+                    "action": [],
+                },
+            })
+        };
+        let mut automod: Automod = serde_json::from_value(json!({
+            "global": settings("global"),
+            "channels": { "20": settings("general"), "21": settings("logs") },
+        }))
+        .unwrap();
+
+        ids.remap_automod(&mut automod, &mut unmapped);
+
+        let global = automod.global.as_ref().unwrap();
+        let bypass = global.bypass.as_ref().unwrap();
+        assert_eq!(bypass.roles, [id(110)]);
+        assert_eq!(bypass.users, [id(20)]);
+        assert_eq!(bypass.groups, ["mods"]);
+        let word = &global.censors[&CensorType::Word];
+        assert_eq!(word.filters, ["10", "20"]);
+        assert!(word.bypass.as_ref().unwrap().roles.is_empty());
+        let spam = global.spam.as_ref().unwrap();
+        assert_eq!(spam.filters[&SpamType::Message].interval, 10);
+        assert!(spam.bypass.as_ref().unwrap().roles.is_empty());
+
+        let channels: Vec<_> = automod.channels.keys().copied().collect();
+        assert_eq!(channels, [id(120)]);
+
+        let paths: Vec<_> = unmapped.iter().map(|u| u.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "/automod/global/censors/word/bypass/roles/0",
+                "/automod/global/spam/bypass/roles/0",
+                "/automod/channels/20/censors/word/bypass/roles/0",
+                "/automod/channels/20/spam/bypass/roles/0",
+                "/automod/channels/21",
+            ]
+        );
+    }
+}
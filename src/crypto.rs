@@ -0,0 +1,144 @@
+use aes_gcm::{
+    aead::{rand_core::RngCore, Aead, OsRng, Payload},
+    AeadCore, Aes256Gcm, KeyInit, Nonce,
+};
+use base64::{
+    engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD},
+    Engine,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::{self, Error, ErrorKind};
+
+use crate::error::ApiError;
+
+const NONCE_LEN: usize = 12;
+
+/// Generate a URL-safe random token with `len` bytes of entropy.
+pub fn random_token(len: usize) -> String {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+/// Unpadded base64url SHA-256 digest.
+pub fn digest(data: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(data))
+}
+
+/// SHA-256 digest of a bearer secret, for storing tokens without keeping them.
+pub fn hash_token(token: &str) -> String {
+    digest(token.as_bytes())
+}
+
+/// AES-256-GCM envelope for secrets persisted in Redis (e.g. Discord OAuth
+/// tokens). Ciphertexts are bound to an associated-data string, normally the
+/// ID of the record that owns them, so they can't be moved between records.
+pub struct SecretBox {
+    cipher: Aes256Gcm,
+}
+
+impl SecretBox {
+    /// Build from a base64-encoded 32-byte key.
+    pub fn from_base64_key(key: &str) -> io::Result<Self> {
+        let invalid = |msg: &str| Error::new(ErrorKind::InvalidInput, msg.to_string());
+
+        let bytes = STANDARD
+            .decode(key.trim())
+            .map_err(|_| invalid("SESSION_ENCRYPTION_KEY must be valid base64"))?;
+        let cipher = Aes256Gcm::new_from_slice(&bytes)
+            .map_err(|_| invalid("SESSION_ENCRYPTION_KEY must decode to 32 bytes"))?;
+
+        Ok(Self { cipher })
+    }
+
+    pub fn seal<T: Serialize>(&self, value: &T, aad: &str) -> Result<String, ApiError> {
+        let plaintext = serde_json::to_vec(value)
+            .map_err(|e| ApiError::Internal(format!("Failed to serialize secret: {e}")))?;
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &plaintext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| ApiError::Internal("Failed to encrypt secret".into()))?;
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        Ok(STANDARD.encode(sealed))
+    }
+
+    pub fn open<T: DeserializeOwned>(&self, sealed: &str, aad: &str) -> Result<T, ApiError> {
+        let bytes = STANDARD
+            .decode(sealed)
+            .map_err(|_| ApiError::Internal("Malformed sealed secret".into()))?;
+        let Some((nonce, ciphertext)) = bytes.split_first_chunk::<NONCE_LEN>() else {
+            return Err(ApiError::Internal("Malformed sealed secret".into()));
+        };
+
+        let plaintext = self
+            .cipher
+            .decrypt(
+                &Nonce::from(*nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: aad.as_bytes(),
+                },
+            )
+            .map_err(|_| ApiError::Internal("Failed to decrypt secret".into()))?;
+
+        serde_json::from_slice(&plaintext)
+            .map_err(|e| ApiError::Internal(format!("Failed to deserialize secret: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secret_box(byte: u8) -> SecretBox {
+        SecretBox::from_base64_key(&STANDARD.encode([byte; 32])).unwrap()
+    }
+
+    #[test]
+    fn tokens_hash_to_base64url_sha256() {
+        assert_eq!(
+            hash_token("abc"),
+            "ungWv48Bz-pBQUDeXa4iI7ADYaOWF3qctBD_YfIAFa0"
+        );
+    }
+
+    #[test]
+    fn sealed_secrets_open_with_the_same_aad() {
+        let secrets = secret_box(1);
+        let sealed = secrets.seal(&"token", "session-1").unwrap();
+
+        assert!(!sealed.contains("token"));
+        assert_eq!(
+            secrets.open::<String>(&sealed, "session-1").unwrap(),
+            "token"
+        );
+    }
+
+    #[test]
+    fn sealed_secrets_are_bound_to_their_aad_and_key() {
+        let sealed = secret_box(1).seal(&"token", "session-1").unwrap();
+
+        assert!(secret_box(1).open::<String>(&sealed, "session-2").is_err());
+        assert!(secret_box(2).open::<String>(&sealed, "session-1").is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_keys_and_ciphertexts() {
+        assert!(SecretBox::from_base64_key("not base64!").is_err());
+        assert!(SecretBox::from_base64_key(&STANDARD.encode([0u8; 16])).is_err());
+
+        let secrets = secret_box(1);
+        assert!(secrets.open::<String>("", "session-1").is_err());
+        assert!(secrets.open::<String>("not base64!", "session-1").is_err());
+    }
+}
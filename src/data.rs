@@ -8,11 +8,17 @@ use bm_lib::{
 };
 use tracing::instrument;
 
-use crate::State;
+use crate::{api::config_etag, State};
 
 const CONFIG_TTL: Duration = Duration::from_secs(60);
 const USER_TTL: Duration = Duration::from_secs(600);
 
+/// How long a config write may hold its guild's lock.
+const CONFIG_LOCK_TTL: Duration = Duration::from_secs(10);
+
+/// How long a config write waits for another write to the same guild.
+const CONFIG_LOCK_WAIT: Duration = Duration::from_secs(5);
+
 #[inline]
 fn user_cache_key(user_id: &Id) -> String {
     format!("user:{}", user_id)
@@ -28,11 +34,19 @@ fn roles_cache_key(guild_id: &Id, user_id: &Id) -> String {
     format!("roles:{}:{}", guild_id, user_id)
 }
 
+/// Set of the bot's command names, published by the bot.
+const BOT_COMMANDS_KEY: &str = "commands";
+
 #[inline]
 fn member_guilds_cache_key(user_id: &Id) -> String {
     format!("member_guilds:{}", user_id)
 }
 
+#[inline]
+fn config_lock_key(guild_id: &Id) -> String {
+    format!("config_lock:{}", guild_id)
+}
+
 #[inline]
 fn channels_cache_key(guild_id: &Id) -> String {
     format!("channels:{}", guild_id)
@@ -79,17 +93,128 @@ impl State {
             }
         };
 
+        self.bot_cache
+            .set(guild_id, &config, Some(CONFIG_TTL))
+            .await?;
+
         Ok(Some(config))
     }
 
-    #[instrument(skip(self))]
-    pub async fn update_config(&self, guild_id: &Id, update: &Config) -> Result<Config, ApiError> {
-        let config = self.db.update_config(&guild_id, &update).await?;
+    /// Persist a config edited from `base` and record it as a new version in
+    /// the guild's history. The version is recorded before the write, so if
+    /// that fails, nothing is written.
+    ///
+    /// Writes to a guild's config are serialized, and fail with
+    /// [`ApiError::PreconditionFailed`] if the config no longer matches `base`,
+    /// so an edit can't overwrite a change made since `base` was read.
+    ///
+    /// The saved config is written through to the bot's cache, which is where
+    /// both the bot and [`State::get_config`] read it, and the bot is notified
+    /// to reload it.
+    #[instrument(skip(self, base, update))]
+    pub async fn update_config(
+        &self,
+        guild_id: &Id,
+        base: &Config,
+        update: &Config,
+        editor: &Id,
+        reason: Option<&str>,
+    ) -> Result<Config, ApiError> {
+        let lock = self
+            .store
+            .lock(
+                &config_lock_key(guild_id),
+                CONFIG_LOCK_TTL,
+                CONFIG_LOCK_WAIT,
+            )
+            .await?
+            .ok_or_else(|| {
+                ApiError::Conflict("The config is being updated, please retry".into())
+            })?;
 
-        self.cache
+        let result = self
+            .update_config_locked(guild_id, base, update, editor, reason)
+            .await;
+
+        if let Err(e) = self.store.unlock(lock).await {
+            tracing::warn!(error = %e, "Failed to release config lock");
+        }
+
+        result
+    }
+
+    /// [`State::update_config`] while holding the guild's config lock.
+    async fn update_config_locked(
+        &self,
+        guild_id: &Id,
+        base: &Config,
+        update: &Config,
+        editor: &Id,
+        reason: Option<&str>,
+    ) -> Result<Config, ApiError> {
+        let previous = self
+            .get_config(guild_id)
+            .await?
+            .ok_or_else(|| ApiError::NotFound("Config not found".into()))?;
+        let current_etag = config_etag(&previous)?;
+        if current_etag != config_etag(base)? {
+            return Err(ApiError::PreconditionFailed(current_etag));
+        }
+
+        let version = self
+            .record_config_version(guild_id, Some(&previous), update, editor, reason)
+            .await?;
+        let config = match self.db.update_config(&guild_id, &update).await {
+            Ok(config) => config,
+            Err(e) => {
+                self.discard_failed_version(guild_id, version).await;
+                return Err(e.into());
+            }
+        };
+
+        self.bot_cache
             .set(guild_id, &config, Some(CONFIG_TTL))
             .await
             .map_err(ApiError::from)?;
+        self.events.config_updated(guild_id).await;
+
+        Ok(config)
+    }
+
+    /// Take back the history version recorded for a write that failed.
+    async fn discard_failed_version(&self, guild_id: &Id, version: u64) {
+        if let Err(e) = self.discard_config_version(guild_id, version).await {
+            tracing::warn!(error = %e, version, "Failed to discard config version");
+        }
+    }
+
+    /// Save the first config for a guild and record it as version 1 of its
+    /// history. Like [`State::update_config`], the version is recorded first,
+    /// and the config is written through to the bot's cache and the bot is
+    /// notified.
+    #[instrument(skip(self, config), fields(guild_id = %config.id))]
+    pub async fn create_config(
+        &self,
+        config: &Config,
+        editor: &Id,
+        reason: Option<&str>,
+    ) -> Result<Config, ApiError> {
+        let version = self
+            .record_config_version(&config.id, None, config, editor, reason)
+            .await?;
+        let config = match self.db.create_config(config).await {
+            Ok(created) => created,
+            Err(e) => {
+                self.discard_failed_version(&config.id, version).await;
+                return Err(e.into());
+            }
+        };
+
+        self.bot_cache
+            .set(&config.id, &config, Some(CONFIG_TTL))
+            .await
+            .map_err(ApiError::from)?;
+        self.events.config_updated(&config.id).await;
 
         Ok(config)
     }
@@ -116,6 +241,25 @@ impl State {
             .map_err(ApiError::from)
     }
 
+    /// Channels for a guild: from cache if possible, otherwise fetched from
+    /// Discord and cached.
+    #[instrument(skip(self))]
+    pub async fn guild_channels(&self, guild_id: &Id) -> Result<Vec<Channel>, ApiError> {
+        if let Some(cached) = self.get_channels(guild_id).await? {
+            return Ok(cached);
+        }
+
+        let fetched = self
+            .bot
+            .get_guild_channels(guild_id)
+            .await
+            .map_err(|e| ApiError::Internal(format!("Discord API error: {}", e)))?;
+
+        self.set_channels(guild_id, &fetched).await?;
+
+        Ok(fetched)
+    }
+
     #[instrument(skip(self))]
     pub async fn get_member_roles(
         &self,
@@ -129,6 +273,16 @@ impl State {
             .map_err(ApiError::from)
     }
 
+    /// Names of the bot's commands, which command aliases may point at. Empty
+    /// until the bot has published them.
+    #[instrument(skip(self))]
+    pub async fn get_bot_commands(&self) -> Result<Vec<String>, ApiError> {
+        self.bot_cache
+            .smembers(BOT_COMMANDS_KEY)
+            .await
+            .map_err(ApiError::from)
+    }
+
     /// Returns the set of guild IDs the bot has observed this user in, using
     /// the `member_guilds:{user_id}` reverse index written by the bot on every
     /// `GuildMemberUpdate` event.  O(1) - no keyspace scan.
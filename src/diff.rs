@@ -0,0 +1,341 @@
+use actix_web::{get, post, web};
+use bm_lib::{discord::Id, model::Config, permissions::Permission};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::instrument;
+
+use crate::{auth::AuthenticatedUser, error::ApiError, State};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// One changed field. `path` is a JSON Pointer (RFC 6901) to the field in the
+/// new document, or in the old one for removed fields, e.g.
+/// `/permission_groups/0/permissions`.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldChange {
+    pub path: String,
+    pub kind: ChangeKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub after: Option<Value>,
+}
+
+/// Field-level differences between two configs.
+pub fn diff_configs(before: &Config, after: &Config) -> Result<Vec<FieldChange>, ApiError> {
+    let to_value = |config: &Config| {
+        serde_json::to_value(config)
+            .map_err(|e| ApiError::Internal(format!("Failed to serialize config: {e}")))
+    };
+
+    Ok(diff_values(&to_value(before)?, &to_value(after)?))
+}
+
+/// Field-level differences between two JSON documents.
+pub fn diff_values(before: &Value, after: &Value) -> Vec<FieldChange> {
+    let mut changes = Vec::new();
+    diff_into(&mut changes, String::new(), Some(before), Some(after));
+    changes
+}
+
+fn diff_into(
+    changes: &mut Vec<FieldChange>,
+    path: String,
+    before: Option<&Value>,
+    after: Option<&Value>,
+) {
+    match (before, after) {
+        (Some(before), Some(after)) if before == after => {}
+        (Some(Value::Object(before)), Some(Value::Object(after))) => {
+            diff_objects(changes, &path, before, after)
+        }
+        (Some(Value::Array(before)), Some(Value::Array(after))) => {
+            diff_arrays(changes, &path, before, after)
+        }
+        (before, after) => {
+            let kind = match (before, after) {
+                (None, _) => ChangeKind::Added,
+                (_, None) => ChangeKind::Removed,
+                _ => ChangeKind::Changed,
+            };
+            changes.push(FieldChange {
+                path,
+                kind,
+                before: before.cloned(),
+                after: after.cloned(),
+            });
+        }
+    }
+}
+
+/// Append one reference token to a JSON Pointer, escaping `~` and `/`.
+pub fn pointer_push(path: &str, token: &str) -> String {
+    format!("{path}/{}", token.replace('~', "~0").replace('/', "~1"))
+}
+
+fn diff_objects(
+    changes: &mut Vec<FieldChange>,
+    path: &str,
+    before: &Map<String, Value>,
+    after: &Map<String, Value>,
+) {
+    let mut keys: Vec<&String> = before.keys().chain(after.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    for key in keys {
+        diff_into(
+            changes,
+            pointer_push(path, key),
+            before.get(key),
+            after.get(key),
+        );
+    }
+}
+
+/// Identity of a list entry: its `name` or `id` field, if it has one.
+fn entry_key(value: &Value) -> Option<String> {
+    let object = value.as_object()?;
+    match object.get("name").or_else(|| object.get("id"))? {
+        Value::String(key) => Some(key.clone()),
+        Value::Number(key) => Some(key.to_string()),
+        _ => None,
+    }
+}
+
+fn diff_arrays(changes: &mut Vec<FieldChange>, path: &str, before: &[Value], after: &[Value]) {
+    let before_keys: Option<Vec<String>> = before.iter().map(entry_key).collect();
+    let after_keys: Option<Vec<String>> = after.iter().map(entry_key).collect();
+
+    // Match named entries (e.g. permission groups) by name so reordering or
+    // inserting one doesn't show up as every later entry changing. Paths
+    // point at the entry's index in the new list, or the old one if removed.
+    if let (Some(before_keys), Some(after_keys)) = (before_keys, after_keys) {
+        let unique = |keys: &[String]| {
+            let mut sorted = keys.to_vec();
+            sorted.sort();
+            sorted.dedup();
+            sorted.len() == keys.len()
+        };
+
+        if unique(&before_keys) && unique(&after_keys) {
+            for (i, (key, value)) in before_keys.iter().zip(before).enumerate() {
+                match after_keys.iter().position(|k| k == key) {
+                    Some(j) => diff_into(
+                        changes,
+                        pointer_push(path, &j.to_string()),
+                        Some(value),
+                        Some(&after[j]),
+                    ),
+                    None => diff_into(
+                        changes,
+                        pointer_push(path, &i.to_string()),
+                        Some(value),
+                        None,
+                    ),
+                }
+            }
+            for (j, (key, value)) in after_keys.iter().zip(after).enumerate() {
+                if !before_keys.contains(key) {
+                    diff_into(
+                        changes,
+                        pointer_push(path, &j.to_string()),
+                        None,
+                        Some(value),
+                    );
+                }
+            }
+            return;
+        }
+    }
+
+    for i in 0..before.len().max(after.len()) {
+        diff_into(
+            changes,
+            pointer_push(path, &i.to_string()),
+            before.get(i),
+            after.get(i),
+        );
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DiffParams {
+    pub from: u64,
+    /// Defaults to the current config.
+    pub to: Option<u64>,
+}
+
+impl State {
+    async fn config_at_version(&self, guild_id: &Id, version: u64) -> Result<Config, ApiError> {
+        self.get_config_version(guild_id, version)
+            .await?
+            .map(|snapshot| snapshot.config)
+            .ok_or_else(|| ApiError::NotFound(format!("Config version {} not found", version)))
+    }
+}
+
+/// `GET /api/config/{id}/diff?from=&to=` - what changed between two saved
+/// versions, or between a version and the current config when `to` is omitted.
+#[get("/api/config/{id}/diff")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn get_config_diff(
+    state: web::Data<State>,
+    id: web::Path<String>,
+    params: web::Query<DiffParams>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Vec<FieldChange>>, ApiError> {
+    let id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".to_string()))?;
+
+    let (_, current) = state
+        .require_guild_permission(&user, &id, Permission::CONFIG_VIEW)
+        .await?;
+
+    let from = state.config_at_version(&id, params.from).await?;
+    let to = match params.to {
+        Some(version) => state.config_at_version(&id, version).await?,
+        None => current,
+    };
+
+    Ok(web::Json(diff_configs(&from, &to)?))
+}
+
+/// `POST /api/config/{id}/diff` - dry run: what saving the given config would
+/// change, without saving it.
+#[post("/api/config/{id}/diff")]
+#[instrument(skip(state, user, proposed), fields(user_id = %user.user_id))]
+pub async fn preview_config_diff(
+    state: web::Data<State>,
+    id: web::Path<String>,
+    proposed: web::Json<Config>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Vec<FieldChange>>, ApiError> {
+    let id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".to_string()))?;
+
+    let (_, current) = state
+        .require_guild_permission(&user, &id, Permission::CONFIG_VIEW)
+        .await?;
+
+    Ok(web::Json(diff_configs(&current, &proposed)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn paths(changes: &[FieldChange]) -> Vec<(&str, ChangeKind)> {
+        changes.iter().map(|c| (c.path.as_str(), c.kind)).collect()
+    }
+
+    #[test]
+    fn equal_documents_have_no_changes() {
+        let doc = json!({ "prefix": "!", "aliases": { "w": "warn" } });
+        assert!(diff_values(&doc, &doc).is_empty());
+    }
+
+    #[test]
+    fn diffs_nested_objects() {
+        let before =
+            json!({ "prefix": "!", "automod": { "global": { "enabled": true, "name": "g" } } });
+        let after =
+            json!({ "prefix": "?", "automod": { "global": { "enabled": false } }, "music": true });
+
+        let changes = diff_values(&before, &after);
+        assert_eq!(
+            paths(&changes),
+            [
+                ("/automod/global/enabled", ChangeKind::Changed),
+                ("/automod/global/name", ChangeKind::Removed),
+                ("/music", ChangeKind::Added),
+                ("/prefix", ChangeKind::Changed),
+            ]
+        );
+        assert_eq!(changes[0].before, Some(json!(true)));
+        assert_eq!(changes[0].after, Some(json!(false)));
+        assert_eq!(changes[1].after, None);
+        assert_eq!(changes[2].before, None);
+    }
+
+    #[test]
+    fn diffs_arrays_that_grow_and_shrink() {
+        let grown = diff_values(&json!({ "f": ["a"] }), &json!({ "f": ["a", "b", "c"] }));
+        assert_eq!(
+            paths(&grown),
+            [("/f/1", ChangeKind::Added), ("/f/2", ChangeKind::Added)]
+        );
+
+        let shrunk = diff_values(&json!({ "f": ["a", "b", "c"] }), &json!({ "f": ["x"] }));
+        assert_eq!(
+            paths(&shrunk),
+            [
+                ("/f/0", ChangeKind::Changed),
+                ("/f/1", ChangeKind::Removed),
+                ("/f/2", ChangeKind::Removed),
+            ]
+        );
+    }
+
+    #[test]
+    fn matches_named_entries_by_name() {
+        let before = json!({ "groups": [
+            { "name": "mods", "permissions": 1 },
+            { "name": "admins", "permissions": 2 },
+        ] });
+        let after = json!({ "groups": [
+            { "name": "helpers", "permissions": 4 },
+            { "name": "admins", "permissions": 3 },
+        ] });
+
+        assert_eq!(
+            paths(&diff_values(&before, &after)),
+            [
+                ("/groups/0", ChangeKind::Removed),
+                ("/groups/1/permissions", ChangeKind::Changed),
+                ("/groups/0", ChangeKind::Added),
+            ]
+        );
+    }
+
+    #[test]
+    fn reordering_named_entries_is_not_a_change() {
+        let before = json!([{ "name": "a" }, { "name": "b" }]);
+        let after = json!([{ "name": "b" }, { "name": "a" }]);
+
+        assert!(diff_values(&before, &after).is_empty());
+    }
+
+    #[test]
+    fn escapes_special_characters_in_keys() {
+        let before = json!({ "censors": { "a/b": 1, "c~d": 1, "e.f[0]": 1 } });
+        let after = json!({ "censors": { "a/b": 2, "c~d": 2, "e.f[0]": 2 } });
+
+        let changes = diff_values(&before, &after);
+        assert_eq!(
+            paths(&changes),
+            [
+                ("/censors/a~1b", ChangeKind::Changed),
+                ("/censors/c~0d", ChangeKind::Changed),
+                ("/censors/e.f[0]", ChangeKind::Changed),
+            ]
+        );
+        for change in &changes {
+            assert_eq!(after.pointer(&change.path), Some(&json!(2)));
+        }
+    }
+
+    #[test]
+    fn replacing_the_root_uses_the_empty_pointer() {
+        assert_eq!(
+            paths(&diff_values(&json!(1), &json!("x"))),
+            [("", ChangeKind::Changed)]
+        );
+    }
+}
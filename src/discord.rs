@@ -1,12 +1,16 @@
+use bm_lib::discord::Id;
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
 const API_BASE: &str = "https://discord.com/api/v10";
+const AUTHORIZE_URL: &str = "https://discord.com/oauth2/authorize";
 
 pub struct RestClient {
     client_id: String,
     client_secret: String,
     redirect_uri: String,
+    bot_token: String,
     client: reqwest::Client,
 }
 
@@ -20,12 +24,18 @@ pub struct DiscordOAuthResponse {
 }
 
 impl RestClient {
-    pub fn new(client_id: String, client_secret: String, redirect_uri: String) -> Self {
+    pub fn new(
+        client_id: String,
+        client_secret: String,
+        redirect_uri: String,
+        bot_token: String,
+    ) -> Self {
         Self {
             client: reqwest::Client::new(),
             client_id,
             client_secret,
             redirect_uri,
+            bot_token,
         }
     }
 
@@ -38,10 +48,36 @@ impl RestClient {
             .await
     }
 
-    #[instrument(skip(self, code))]
+    /// Build the Discord authorization URL users are sent to, carrying the
+    /// CSRF `state` and an S256 PKCE `code_challenge`.
+    pub fn authorize_url(
+        &self,
+        state: &str,
+        code_challenge: &str,
+        redirect_uri_override: Option<&str>,
+    ) -> String {
+        let redirect = redirect_uri_override.unwrap_or(&self.redirect_uri);
+        reqwest::Url::parse_with_params(
+            AUTHORIZE_URL,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.client_id),
+                ("scope", "identify guilds"),
+                ("redirect_uri", redirect),
+                ("state", state),
+                ("code_challenge", code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .expect("AUTHORIZE_URL is a valid URL")
+        .to_string()
+    }
+
+    #[instrument(skip(self, code, code_verifier))]
     pub async fn oauth_token(
         &self,
         code: String,
+        code_verifier: String,
         redirect_uri_override: Option<String>,
     ) -> Result<DiscordOAuthResponse, reqwest::Error> {
         let redirect = redirect_uri_override.unwrap_or_else(|| self.redirect_uri.clone());
@@ -54,6 +90,7 @@ impl RestClient {
                 ("client_secret", self.client_secret.clone()),
                 ("grant_type", "authorization_code".to_owned()),
                 ("code", code),
+                ("code_verifier", code_verifier),
                 ("redirect_uri", redirect),
                 ("scope", "identify guilds".to_string()),
             ])
@@ -86,7 +123,35 @@ impl RestClient {
             .send()
             .await?;
 
-        response.json().await
+        // Discord answers a revoked/expired refresh token with 400 `invalid_grant`;
+        // surface that as a status error rather than a JSON decode failure.
+        response.error_for_status()?.json().await
+    }
+
+    /// Whether `user_id` is a member of the guild, asked as the bot. Discord
+    /// answers `404 Unknown Member` for users who aren't.
+    #[instrument(skip(self))]
+    pub async fn is_guild_member(
+        &self,
+        guild_id: &Id,
+        user_id: &Id,
+    ) -> Result<bool, reqwest::Error> {
+        let response = self
+            .client
+            .get(format!(
+                "{}/guilds/{}/members/{}",
+                API_BASE, guild_id, user_id
+            ))
+            .header("Authorization", format!("Bot {}", self.bot_token))
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+        response.error_for_status()?;
+
+        Ok(true)
     }
 }
 
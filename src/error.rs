@@ -1,6 +1,11 @@
-use actix_web::{HttpResponse, ResponseError};
+use actix_web::{
+    http::{header, StatusCode},
+    HttpResponse, ResponseError,
+};
 use thiserror::Error;
 
+use crate::validation::FieldError;
+
 #[derive(Error, Debug)]
 pub enum ApiError {
     #[error("Discord API error: {0}")]
@@ -12,12 +17,18 @@ pub enum ApiError {
     #[error("Cache error: {0}")]
     Cache(#[from] bm_lib::cache::RedisCacheError),
 
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+
     #[error("Parse error: {0}")]
     ParseError(String),
 
     #[error("Authentication error: {0}")]
     Auth(String),
 
+    #[error("Re-authentication required: {0}")]
+    ReauthRequired(String),
+
     #[error("Forbidden: {0}")]
     Forbidden(String),
 
@@ -27,6 +38,19 @@ pub enum ApiError {
     #[error("Not found: {0}")]
     NotFound(String),
 
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
+    /// The resource changed since the client read it; carries the current ETag.
+    #[error("Precondition failed: resource was modified, current ETag is {0}")]
+    PreconditionFailed(String),
+
+    #[error("Validation failed for {} field(s)", .0.len())]
+    Validation(Vec<FieldError>),
+
+    #[error("Precondition required: {0}")]
+    PreconditionRequired(String),
+
     #[error("Internal server error: {0}")]
     Internal(String),
 }
@@ -35,10 +59,22 @@ impl ResponseError for ApiError {
     fn error_response(&self) -> HttpResponse {
         match self {
             ApiError::Auth(_) => HttpResponse::Unauthorized().json(self.to_string()),
+            ApiError::ReauthRequired(_) => HttpResponse::Unauthorized().json(self.to_string()),
             ApiError::Forbidden(_) => HttpResponse::Forbidden().json(self.to_string()),
             ApiError::BadRequest(_) => HttpResponse::BadRequest().json(self.to_string()),
             ApiError::NotFound(_) => HttpResponse::NotFound().json(self.to_string()),
+            ApiError::Conflict(_) => HttpResponse::Conflict().json(self.to_string()),
             ApiError::ParseError(_) => HttpResponse::BadRequest().json(self.to_string()),
+            ApiError::Validation(errors) => HttpResponse::BadRequest().json(serde_json::json!({
+                "error": self.to_string(),
+                "errors": errors,
+            })),
+            ApiError::PreconditionFailed(etag) => HttpResponse::PreconditionFailed()
+                .insert_header((header::ETAG, etag.as_str()))
+                .json(self.to_string()),
+            ApiError::PreconditionRequired(_) => {
+                HttpResponse::build(StatusCode::PRECONDITION_REQUIRED).json(self.to_string())
+            }
             _ => HttpResponse::InternalServerError().json(self.to_string()),
         }
     }
@@ -0,0 +1,52 @@
+use bm_lib::discord::Id;
+use redis::{aio::ConnectionManager, AsyncCommands, Client, RedisResult};
+use serde::Serialize;
+use tracing::instrument;
+
+/// Redis pub/sub channel, under the bot's prefix, that tells the bot a guild's
+/// config changed and should be reloaded.
+const CONFIG_UPDATED_CHANNEL: &str = "config_updated";
+
+#[derive(Debug, Serialize)]
+struct ConfigUpdated<'a> {
+    guild_id: &'a Id,
+}
+
+/// Publishes change notifications to the bot over Redis pub/sub.
+pub struct EventPublisher {
+    conn: ConnectionManager,
+    config_updated_channel: String,
+}
+
+impl EventPublisher {
+    pub async fn connect(redis_uri: &str, bot_prefix: &str) -> RedisResult<Self> {
+        let conn = Client::open(redis_uri)?.get_connection_manager().await?;
+
+        Ok(Self {
+            conn,
+            config_updated_channel: format!("{}:{}", bot_prefix, CONFIG_UPDATED_CHANNEL),
+        })
+    }
+
+    /// Tell the bot to reload a guild's config. Failures are logged rather
+    /// than returned: the config is already saved and cached, so the bot will
+    /// pick it up on its next read either way.
+    #[instrument(skip(self))]
+    pub async fn config_updated(&self, guild_id: &Id) {
+        let payload = match serde_json::to_string(&ConfigUpdated { guild_id }) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to serialize config_updated event");
+                return;
+            }
+        };
+
+        let mut conn = self.conn.clone();
+        if let Err(e) = conn
+            .publish::<_, _, ()>(&self.config_updated_channel, payload)
+            .await
+        {
+            tracing::warn!(error = %e, "Failed to publish config_updated event");
+        }
+    }
+}
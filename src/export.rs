@@ -0,0 +1,673 @@
+use std::collections::BTreeMap;
+
+use actix_web::{get, http::header, post, web, HttpMessage, HttpRequest, HttpResponse};
+use bm_lib::{
+    discord::Id,
+    model::{logging::LogConfig, Config},
+    permissions::Permission,
+};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::instrument;
+
+use crate::{
+    api::{config_etag, config_response, require_if_match},
+    auth::AuthenticatedUser,
+    diff::{diff_configs, diff_values, FieldChange},
+    error::ApiError,
+    validation, State,
+};
+
+/// File format of an exported config document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentFormat {
+    #[default]
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl DocumentFormat {
+    fn content_type(self) -> &'static str {
+        match self {
+            DocumentFormat::Json => "application/json",
+            DocumentFormat::Yaml => "application/yaml",
+            DocumentFormat::Toml => "application/toml",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            DocumentFormat::Json => "json",
+            DocumentFormat::Yaml => "yaml",
+            DocumentFormat::Toml => "toml",
+        }
+    }
+
+    fn from_content_type(content_type: &str) -> Option<Self> {
+        match content_type {
+            "" | "application/json" => Some(DocumentFormat::Json),
+            "application/yaml" | "application/x-yaml" | "text/yaml" => Some(DocumentFormat::Yaml),
+            "application/toml" | "text/toml" => Some(DocumentFormat::Toml),
+            _ => None,
+        }
+    }
+}
+
+/// A log event's settings in a config document, where entries are keyed by
+/// event type.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub enabled: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel_id: Option<Id>,
+    #[serde(default)]
+    pub embed: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text_content: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embed_title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embed_body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embed_color: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embed_footer: Option<String>,
+}
+
+impl LogEntry {
+    fn from_log_config(log: &LogConfig) -> Self {
+        Self {
+            enabled: log.enabled,
+            channel_id: log.channel_id,
+            embed: log.embed,
+            text_content: log.text_content.clone(),
+            embed_title: log.embed_title.clone(),
+            embed_body: log.embed_body.clone(),
+            embed_color: log.embed_color,
+            embed_footer: log.embed_footer.clone(),
+        }
+    }
+
+    fn into_log_config(self, guild_id: Id, event: String) -> LogConfig {
+        LogConfig {
+            id: None,
+            guild_id,
+            event,
+            enabled: self.enabled,
+            channel_id: self.channel_id,
+            embed: self.embed,
+            text_content: self.text_content,
+            embed_title: self.embed_title,
+            embed_body: self.embed_body,
+            embed_color: self.embed_color,
+            embed_footer: self.embed_footer,
+        }
+    }
+}
+
+/// Top-level document keys in export order, with the comment written above
+/// each in YAML and TOML exports.
+const FIELD_COMMENTS: &[(&str, &str)] = &[
+    ("prefix", "Command prefix used to trigger bot commands."),
+    ("mute_role", "Role applied by the `mute` command."),
+    (
+        "default_warn_duration",
+        "Default warn expiry in seconds when `warn` is given no duration.",
+    ),
+    (
+        "log_channel",
+        "Channel where moderation action embeds are posted.",
+    ),
+    (
+        "prefer_embeds",
+        "Use embeds for bot responses where supported.",
+    ),
+    (
+        "inherit_discord_perms",
+        "Grant Black Mesa permissions from members' Discord permissions.",
+    ),
+    (
+        "alert_on_infraction",
+        "DM the target user when an infraction is issued.",
+    ),
+    (
+        "send_permission_denied",
+        "Reply when a caller lacks permission for a command.",
+    ),
+    ("moderation_enabled", "Enable moderation commands."),
+    ("music_enabled", "Enable music playback commands."),
+    ("automod_enabled", "Enable the automod engine."),
+    (
+        "permission_groups",
+        "Permission groups: the roles and users in each, and the permissions it grants.",
+    ),
+    (
+        "automod",
+        "Automod settings, used when `automod_enabled` is true.",
+    ),
+    (
+        "command_aliases",
+        "Command aliases, mapped to the command they run.",
+    ),
+    ("logging", "Event logging, keyed by event type."),
+];
+
+/// A guild's config as a portable document: the config fields without `id`,
+/// plus the guild's log configs under `logging`. Nulls are dropped since TOML
+/// can't express them; missing fields read back as unset.
+pub fn config_document(config: &Config, logs: &[LogConfig]) -> Result<Value, ApiError> {
+    let serialize =
+        |e: serde_json::Error| ApiError::Internal(format!("Failed to serialize config: {e}"));
+
+    let mut document = serde_json::to_value(config).map_err(serialize)?;
+    let object = document
+        .as_object_mut()
+        .ok_or_else(|| ApiError::Internal("Config must serialize to an object".into()))?;
+    object.remove("id");
+
+    let logging: BTreeMap<&str, LogEntry> = logs
+        .iter()
+        .map(|log| (log.event.as_str(), LogEntry::from_log_config(log)))
+        .collect();
+    object.insert(
+        "logging".into(),
+        serde_json::to_value(logging).map_err(serialize)?,
+    );
+
+    strip_nulls(&mut document);
+    Ok(document)
+}
+
+/// Remove null fields from objects, recursively.
+pub fn strip_nulls(value: &mut Value) {
+    match value {
+        Value::Object(object) => {
+            object.retain(|_, v| !v.is_null());
+            object.values_mut().for_each(strip_nulls);
+        }
+        Value::Array(items) => items.iter_mut().for_each(strip_nulls),
+        _ => {}
+    }
+}
+
+/// Unsigned integer fields of a config document, as JSON Pointers in which
+/// `*` matches any token.
+const NUMERIC_FIELDS: &[&str] = &[
+    "/default_warn_duration",
+    "/permission_groups/*/permissions",
+    "/automod/global/censors/*/action/duration",
+    "/automod/global/spam/filters/*/interval",
+    "/automod/global/spam/filters/*/count",
+    "/automod/global/spam/action/*/duration",
+    "/automod/global/spam/action/*/threshold",
+    "/automod/channels/*/censors/*/action/duration",
+    "/automod/channels/*/spam/filters/*/interval",
+    "/automod/channels/*/spam/filters/*/count",
+    "/automod/channels/*/spam/action/*/duration",
+    "/automod/channels/*/spam/action/*/threshold",
+];
+
+fn is_numeric_field(path: &[String]) -> bool {
+    NUMERIC_FIELDS.iter().any(|pattern| {
+        let tokens: Vec<&str> = pattern.split('/').skip(1).collect();
+        tokens.len() == path.len()
+            && tokens
+                .iter()
+                .zip(path)
+                .all(|(token, key)| *token == "*" || token == key)
+    })
+}
+
+/// Call `f` on every value in `value` at one of the [`NUMERIC_FIELDS`].
+fn for_each_numeric_field(value: &mut Value, path: &mut Vec<String>, f: fn(&mut Value)) {
+    if is_numeric_field(path) {
+        f(value);
+        return;
+    }
+
+    match value {
+        Value::Object(object) => {
+            for (key, value) in object.iter_mut() {
+                path.push(key.clone());
+                for_each_numeric_field(value, path, f);
+                path.pop();
+            }
+        }
+        Value::Array(items) => {
+            for (i, value) in items.iter_mut().enumerate() {
+                path.push(i.to_string());
+                for_each_numeric_field(value, path, f);
+                path.pop();
+            }
+        }
+        _ => {}
+    }
+}
+
+/// TOML integers are signed 64-bit, so larger numbers (e.g. permission bits
+/// with the top bit set) are exported as strings. Only [`NUMERIC_FIELDS`] are
+/// touched, so strings that merely look like numbers are left alone.
+fn stringify_large_numbers(document: &mut Value) {
+    for_each_numeric_field(document, &mut Vec::new(), |value| {
+        if let Value::Number(n) = value {
+            if n.as_u64().is_some_and(|n| n > i64::MAX as u64) {
+                *value = Value::String(n.to_string());
+            }
+        }
+    });
+}
+
+/// Undo [`stringify_large_numbers`] on an imported TOML document.
+fn parse_large_numbers(document: &mut Value) {
+    for_each_numeric_field(document, &mut Vec::new(), |value| {
+        if let Value::String(s) = value {
+            if let Some(n) = s.parse::<u64>().ok().filter(|n| *n > i64::MAX as u64) {
+                *value = Value::from(n);
+            }
+        }
+    });
+}
+
+/// Render a config document. YAML and TOML get a header and a comment above
+/// each top-level field; JSON has no comment syntax.
+fn render_document(
+    document: &Value,
+    format: DocumentFormat,
+    guild_id: &Id,
+) -> Result<String, ApiError> {
+    let mut document = document.clone();
+    if format == DocumentFormat::Toml {
+        stringify_large_numbers(&mut document);
+    }
+
+    let render = |value: &Value| match format {
+        DocumentFormat::Json => serde_json::to_string_pretty(value).map_err(|e| e.to_string()),
+        DocumentFormat::Yaml => serde_yaml::to_string(value).map_err(|e| e.to_string()),
+        DocumentFormat::Toml => toml::to_string_pretty(value).map_err(|e| e.to_string()),
+    };
+    let render = |value: &Value| {
+        render(value).map_err(|e| ApiError::Internal(format!("Failed to render config: {e}")))
+    };
+
+    if format == DocumentFormat::Json {
+        return render(&document);
+    }
+
+    let object = document
+        .as_object()
+        .ok_or_else(|| ApiError::Internal("Config document must be an object".into()))?;
+
+    // Known fields in a stable order, then anything else the config carries.
+    let mut keys: Vec<&str> = FIELD_COMMENTS
+        .iter()
+        .map(|(key, _)| *key)
+        .filter(|key| object.contains_key(*key))
+        .collect();
+    keys.extend(
+        object
+            .keys()
+            .map(String::as_str)
+            .filter(|key| !FIELD_COMMENTS.iter().any(|(known, _)| known == key)),
+    );
+
+    // TOML tables must follow every plain key, or the keys would land in the
+    // table above them.
+    let mut fields = String::new();
+    let mut tables = String::new();
+    for key in keys {
+        let mut entry = Map::new();
+        entry.insert(key.to_string(), object[key].clone());
+        let rendered = render(&Value::Object(entry))?;
+
+        let section = if format == DocumentFormat::Toml && rendered.starts_with('[') {
+            &mut tables
+        } else {
+            &mut fields
+        };
+        section.push('\n');
+        if let Some((_, comment)) = FIELD_COMMENTS.iter().find(|(known, _)| *known == key) {
+            section.push_str(&format!("# {comment}\n"));
+        }
+        section.push_str(&rendered);
+    }
+
+    Ok(format!(
+        "# Black Mesa config for guild {guild_id}, exported {}.\n\
+         # Apply with POST /api/config/{guild_id}/import.\n{fields}{tables}",
+        Utc::now().to_rfc3339(),
+    ))
+}
+
+fn parse_document(body: &[u8], format: DocumentFormat) -> Result<Value, ApiError> {
+    let text = std::str::from_utf8(body)
+        .map_err(|_| ApiError::BadRequest("Config document must be UTF-8".into()))?;
+
+    let parsed = match format {
+        DocumentFormat::Json => serde_json::from_str(text).map_err(|e| e.to_string()),
+        DocumentFormat::Yaml => serde_yaml::from_str(text).map_err(|e| e.to_string()),
+        DocumentFormat::Toml => toml::from_str(text)
+            .map(|mut document| {
+                parse_large_numbers(&mut document);
+                document
+            })
+            .map_err(|e| e.to_string()),
+    };
+    parsed.map_err(|e| ApiError::BadRequest(format!("Invalid config document: {e}")))
+}
+
+/// Read a config document for `guild_id`. Log configs are `None` when the
+/// document has no `logging` section.
+fn read_document(
+    mut document: Value,
+    guild_id: &Id,
+) -> Result<(Config, Option<Vec<LogConfig>>), ApiError> {
+    let object = document
+        .as_object_mut()
+        .ok_or_else(|| ApiError::BadRequest("Config document must be a map of fields".into()))?;
+
+    let logging = object
+        .remove("logging")
+        .map(serde_json::from_value::<BTreeMap<String, LogEntry>>)
+        .transpose()
+        .map_err(|e| ApiError::BadRequest(format!("Invalid logging section: {e}")))?;
+
+    object.insert(
+        "id".into(),
+        serde_json::to_value(guild_id)
+            .map_err(|e| ApiError::Internal(format!("Failed to serialize guild ID: {e}")))?,
+    );
+    let config = serde_json::from_value(document)
+        .map_err(|e| ApiError::BadRequest(format!("Invalid config: {e}")))?;
+
+    let logs = logging.map(|logging| {
+        logging
+            .into_iter()
+            .map(|(event, entry)| entry.into_log_config(*guild_id, event))
+            .collect()
+    });
+
+    Ok((config, logs))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportParams {
+    #[serde(default)]
+    pub format: DocumentFormat,
+}
+
+/// `GET /api/config/{id}/export?format=json|yaml|toml` - download the guild's
+/// config and log configs as a document that can be kept in version control.
+#[get("/api/config/{id}/export")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn export_config(
+    state: web::Data<State>,
+    id: web::Path<String>,
+    params: web::Query<ExportParams>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".to_string()))?;
+
+    let (_, config) = state
+        .require_guild_permission(&user, &id, Permission::CONFIG_VIEW)
+        .await?;
+    let logs = state.db.get_log_configs(&id).await?;
+
+    let document = config_document(&config, &logs)?;
+    let body = render_document(&document, params.format, &id)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(params.format.content_type())
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!(
+                "attachment; filename=\"guild-{}.{}\"",
+                id,
+                params.format.extension()
+            ),
+        ))
+        .insert_header((header::ETAG, config_etag(&config)?))
+        .body(body))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ImportParams {
+    /// Defaults to the request's content type.
+    pub format: Option<DocumentFormat>,
+    /// Return the changes the import would make without applying them.
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// `POST /api/config/{id}/import` - validate and apply an exported document.
+///
+/// The config is replaced by the document's. When the document has a
+/// `logging` section, the guild's log configs are made to match it; otherwise
+/// they are left alone. With `dry_run=true`, returns the field-level changes
+/// instead. Applying requires an `If-Match` header with the config's ETag.
+#[post("/api/config/{id}/import")]
+#[instrument(skip(state, user, req, body), fields(user_id = %user.user_id))]
+pub async fn import_config(
+    state: web::Data<State>,
+    id: web::Path<String>,
+    params: web::Query<ImportParams>,
+    req: HttpRequest,
+    body: web::Bytes,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".to_string()))?;
+
+    let (guild, current) = state
+        .require_guild_permission(&user, &id, Permission::CONFIG_EDIT)
+        .await?;
+
+    let format = match params.format {
+        Some(format) => format,
+        None => DocumentFormat::from_content_type(req.content_type()).ok_or_else(|| {
+            ApiError::BadRequest(
+                "Unsupported content type; set format=json, yaml or toml".to_string(),
+            )
+        })?,
+    };
+    let (config, logs) = read_document(parse_document(&body, format)?, &id)?;
+
+    let channels = state.guild_channels(&id).await?;
+    let commands = state.get_bot_commands().await?;
+    let mut errors = validation::validate_config(&config, &guild, &channels, &commands);
+    if let Some(logs) = &logs {
+        errors.extend(validation::validate_log_configs(logs, &channels));
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+
+    let current_logs = state.db.get_log_configs(&id).await?;
+
+    if params.dry_run {
+        let before = config_document(&current, &current_logs)?;
+        let after = config_document(&config, logs.as_deref().unwrap_or(&current_logs))?;
+        let changes: Vec<FieldChange> = diff_values(&before, &after);
+        return Ok(HttpResponse::Ok().json(changes));
+    }
+
+    require_if_match(&req, &current)?;
+    state
+        .check_escalation(&guild, &current, &config, &user)
+        .await?;
+
+    let config = if diff_configs(&current, &config)?.is_empty() {
+        current
+    } else {
+        state
+            .update_config(&id, &current, &config, &user.user_id, Some("Import"))
+            .await?
+    };
+
+    if let Some(logs) = logs {
+        state.replace_log_configs(&id, &logs).await?;
+    }
+
+    config_response(&config)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn id(n: u64) -> Id {
+        Id::from_str(&n.to_string()).unwrap()
+    }
+
+    fn config() -> Config {
+        serde_json::from_value(json!({
+            "id": id(1),
+            "prefix": "!",
+            "mute_role": id(10),
+            "default_warn_duration": null,
+            "log_channel": id(20),
+            "prefer_embeds": true,
+            "inherit_discord_perms": false,
+            "alert_on_infraction": true,
+            "send_permission_denied": false,
+            "moderation_enabled": true,
+            "music_enabled": false,
+            "automod_enabled": true,
+            "permission_groups": [
+                { "name": "admins", "roles": [id(11)], "users": [], "permissions": Permission::all() },
+                { "name": "helpers", "roles": [], "users": [id(3)], "permissions": Permission::MODERATION_KICK },
+            ],
+            "automod": {
+                "global": {
+                    "name": "global",
+                    "enabled": true,
+                    "spam": {
+                        "enabled": true,
+                        "filters": { "message": { "interval": 5000, "count": 5 } },
+                        "action": [{ "action": "mute", "duration": 300000, "threshold": 3 }],
+                    },
+                },
+            },
+            "command_aliases": { "b": "ban" },
+        }))
+        .unwrap()
+    }
+
+    fn logs() -> Vec<LogConfig> {
+        vec![LogConfig {
+            id: Some(7),
+            guild_id: id(1),
+            event: "member_join".to_string(),
+            enabled: true,
+            channel_id: Some(id(20)),
+            embed: true,
+            text_content: None,
+            embed_title: Some("Joined".to_string()),
+            embed_body: None,
+            embed_color: Some(0xff0000),
+            embed_footer: None,
+        }]
+    }
+
+    #[test]
+    fn documents_round_trip_in_every_format() {
+        let document = config_document(&config(), &logs()).unwrap();
+
+        for format in [
+            DocumentFormat::Json,
+            DocumentFormat::Yaml,
+            DocumentFormat::Toml,
+        ] {
+            let rendered = render_document(&document, format, &id(1)).unwrap();
+            let parsed = parse_document(rendered.as_bytes(), format).unwrap();
+            let (config, logs) = read_document(parsed, &id(1)).unwrap();
+
+            let logs = logs.expect("logging section");
+            assert_eq!(
+                config_document(&config, &logs).unwrap(),
+                document,
+                "{format:?}"
+            );
+            assert_eq!(
+                config.permission_groups.unwrap()[0].permissions,
+                Permission::all(),
+                "{format:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn toml_keeps_numbers_above_i64_max() {
+        let document = json!({
+            "permission_groups": [{ "permissions": u64::MAX }, { "permissions": 1 }],
+            "automod": {
+                "channels": {
+                    "20": { "spam": { "action": [{ "duration": i64::MAX as u64 + 1 }] } },
+                },
+            },
+        });
+
+        let rendered = render_document(&document, DocumentFormat::Toml, &id(1)).unwrap();
+        assert!(rendered.contains(&format!("\"{}\"", u64::MAX)));
+
+        let parsed = parse_document(rendered.as_bytes(), DocumentFormat::Toml).unwrap();
+        assert_eq!(parsed, document);
+    }
+
+    #[test]
+    fn toml_leaves_numeric_strings_alone() {
+        let big = u64::MAX.to_string();
+        let document = json!({
+            "prefix": big,
+            "command_aliases": { "b": big },
+            "automod": {
+                "global": { "censors": { "word": { "filters": [big, "123"] } } },
+            },
+            "permission_groups": [{ "name": big, "permissions": 1 }],
+        });
+
+        let rendered = render_document(&document, DocumentFormat::Toml, &id(1)).unwrap();
+        let parsed = parse_document(rendered.as_bytes(), DocumentFormat::Toml).unwrap();
+        assert_eq!(parsed, document);
+    }
+
+    #[test]
+    fn documents_drop_id_and_nulls() {
+        let document = config_document(&config(), &[]).unwrap();
+        let object = document.as_object().unwrap();
+
+        assert!(!object.contains_key("id"));
+        assert!(!object.contains_key("default_warn_duration"));
+        assert_eq!(object["logging"], json!({}));
+    }
+
+    #[test]
+    fn reading_without_logging_keeps_log_configs() {
+        let mut document = config_document(&config(), &[]).unwrap();
+        document.as_object_mut().unwrap().remove("logging");
+
+        let (config, logs) = read_document(document, &id(5)).unwrap();
+        assert_eq!(config.id, id(5));
+        assert!(logs.is_none());
+    }
+
+    #[test]
+    fn content_types_select_formats() {
+        assert_eq!(
+            DocumentFormat::from_content_type(""),
+            Some(DocumentFormat::Json)
+        );
+        assert_eq!(
+            DocumentFormat::from_content_type("text/yaml"),
+            Some(DocumentFormat::Yaml)
+        );
+        assert_eq!(
+            DocumentFormat::from_content_type("application/toml"),
+            Some(DocumentFormat::Toml)
+        );
+        assert_eq!(DocumentFormat::from_content_type("text/plain"), None);
+    }
+}
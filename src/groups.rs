@@ -0,0 +1,412 @@
+use actix_web::{
+    delete, get,
+    http::{header, StatusCode},
+    post, put, web, HttpRequest, HttpResponse,
+};
+use bm_lib::{
+    discord::{Guild, Id},
+    model::{Config, Group},
+    permissions::Permission,
+};
+use tracing::instrument;
+
+use crate::{
+    api::{config_etag, require_if_match},
+    auth::AuthenticatedUser,
+    error::ApiError,
+    permissions::require_grantable,
+    validation::FieldError,
+    State,
+};
+
+/// Check a permission group's name, that its roles exist in the guild and
+/// that none of its users are in `non_members`, as found by
+/// [`State::find_non_members`].
+fn validate_permission_group(
+    guild: &Guild,
+    group: &Group,
+    non_members: &[Id],
+) -> Result<(), ApiError> {
+    let mut errors = Vec::new();
+    let mut add = |field: String, message: String| errors.push(FieldError { field, message });
+
+    if group.name.trim().is_empty() {
+        add("/name".into(), "must not be empty".into());
+    } else if group.name.trim() != group.name {
+        add(
+            "/name".into(),
+            "must not start or end with whitespace".into(),
+        );
+    }
+
+    for (i, role) in group.roles.iter().enumerate() {
+        if !guild.roles.iter().any(|r| r.id == *role) {
+            add(
+                format!("/roles/{i}"),
+                format!("role {} does not exist in this guild", role),
+            );
+        }
+    }
+
+    for (i, user) in group.users.iter().enumerate() {
+        if non_members.contains(user) {
+            add(
+                format!("/users/{i}"),
+                format!("user {} is not a member of this guild", user),
+            );
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(ApiError::Validation(errors));
+    }
+
+    Ok(())
+}
+
+impl State {
+    /// The `users` who aren't members of the guild. The bot only caches members
+    /// it has seen, so users missing from its cache are looked up on Discord.
+    #[instrument(skip(self, guild, users), fields(guild_id = %guild.id))]
+    async fn find_non_members(&self, guild: &Guild, users: &[Id]) -> Result<Vec<Id>, ApiError> {
+        let mut non_members = Vec::new();
+        for user in users {
+            if guild.owner_id == Some(*user)
+                || self.get_member_roles(&guild.id, user).await?.is_some()
+            {
+                continue;
+            }
+
+            let is_member = self
+                .rest
+                .is_guild_member(&guild.id, user)
+                .await
+                .map_err(ApiError::Discord)?;
+            if !is_member {
+                non_members.push(*user);
+            }
+        }
+
+        Ok(non_members)
+    }
+
+    /// Save `config` with its permission groups replaced by `groups`.
+    async fn save_permission_groups(
+        &self,
+        guild: &Guild,
+        config: &Config,
+        groups: Vec<Group>,
+        user: &AuthenticatedUser,
+        reason: &str,
+    ) -> Result<Config, ApiError> {
+        let mut update = config.clone();
+        update.permission_groups = Some(groups);
+        self.validate_config(&update, guild).await?;
+        self.check_escalation(guild, config, &update, user).await?;
+
+        self.update_config(&config.id, config, &update, &user.user_id, Some(reason))
+            .await
+    }
+}
+
+fn parse_guild_id(id: &str) -> Result<Id, ApiError> {
+    Id::from_str(id).map_err(|_| ApiError::ParseError("Invalid ID".to_string()))
+}
+
+/// Permissions an update to a group grants that it didn't before. New roles
+/// and users gain every permission of the group, not just the added ones.
+fn newly_granted(existing: &Group, updated: &Group) -> Permission {
+    let gains_members = updated.roles.iter().any(|r| !existing.roles.contains(r))
+        || updated.users.iter().any(|u| !existing.users.contains(u));
+
+    if gains_members {
+        updated.permissions
+    } else {
+        updated.permissions - existing.permissions
+    }
+}
+
+fn find_group<'a>(config: &'a Config, name: &str) -> Result<&'a Group, ApiError> {
+    config
+        .permission_groups
+        .iter()
+        .flatten()
+        .find(|group| group.name == name)
+        .ok_or_else(|| ApiError::NotFound(format!("Permission group {} not found", name)))
+}
+
+/// Respond with a group and the ETag of the config it was saved in.
+fn group_response(
+    status: StatusCode,
+    config: &Config,
+    group: &Group,
+) -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::build(status)
+        .insert_header((header::ETAG, config_etag(config)?))
+        .json(group))
+}
+
+/// `GET /api/config/{id}/permission-groups` - list a guild's permission groups.
+#[get("/api/config/{id}/permission-groups")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn list_permission_groups(
+    state: web::Data<State>,
+    id: web::Path<String>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Vec<Group>>, ApiError> {
+    let id = parse_guild_id(&id)?;
+    let (_, config) = state
+        .require_guild_permission(&user, &id, Permission::CONFIG_VIEW)
+        .await?;
+
+    Ok(web::Json(config.permission_groups.unwrap_or_default()))
+}
+
+/// `GET /api/config/{id}/permission-groups/{name}` - fetch one permission group.
+#[get("/api/config/{id}/permission-groups/{name}")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn get_permission_group(
+    state: web::Data<State>,
+    path: web::Path<(String, String)>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Group>, ApiError> {
+    let (id, name) = path.into_inner();
+    let id = parse_guild_id(&id)?;
+    let (_, config) = state
+        .require_guild_permission(&user, &id, Permission::CONFIG_VIEW)
+        .await?;
+
+    Ok(web::Json(find_group(&config, &name)?.clone()))
+}
+
+/// `POST /api/config/{id}/permission-groups` - add a permission group. The
+/// caller can only grant permissions they hold themselves.
+#[post("/api/config/{id}/permission-groups")]
+#[instrument(skip(state, user, req, group), fields(user_id = %user.user_id))]
+pub async fn create_permission_group(
+    state: web::Data<State>,
+    id: web::Path<String>,
+    req: HttpRequest,
+    group: web::Json<Group>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let id = parse_guild_id(&id)?;
+    let group = group.into_inner();
+
+    let (guild, config) = state
+        .require_guild_permission(&user, &id, Permission::CONFIG_EDIT)
+        .await?;
+    require_if_match(&req, &config)?;
+
+    let mut groups = config.permission_groups.clone().unwrap_or_default();
+    if groups.iter().any(|g| g.name == group.name) {
+        return Err(ApiError::Conflict(format!(
+            "Permission group {} already exists",
+            group.name
+        )));
+    }
+    let non_members = state.find_non_members(&guild, &group.users).await?;
+    validate_permission_group(&guild, &group, &non_members)?;
+
+    let held = state.grantable_permissions(&config, &guild, &user).await?;
+    require_grantable(held, group.permissions)?;
+
+    groups.push(group.clone());
+    let reason = format!("Created permission group {}", group.name);
+    let updated = state
+        .save_permission_groups(&guild, &config, groups, &user, &reason)
+        .await?;
+
+    group_response(StatusCode::CREATED, &updated, &group)
+}
+
+/// `PUT /api/config/{id}/permission-groups/{name}` - replace a permission
+/// group, optionally renaming it. The caller must hold the permissions the
+/// group gains, or all of its permissions if it gains roles or users.
+#[put("/api/config/{id}/permission-groups/{name}")]
+#[instrument(skip(state, user, req, group), fields(user_id = %user.user_id))]
+pub async fn update_permission_group(
+    state: web::Data<State>,
+    path: web::Path<(String, String)>,
+    req: HttpRequest,
+    group: web::Json<Group>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let (id, name) = path.into_inner();
+    let id = parse_guild_id(&id)?;
+    let group = group.into_inner();
+
+    let (guild, config) = state
+        .require_guild_permission(&user, &id, Permission::CONFIG_EDIT)
+        .await?;
+    require_if_match(&req, &config)?;
+
+    let existing = find_group(&config, &name)?;
+    if group.name != name
+        && config
+            .permission_groups
+            .iter()
+            .flatten()
+            .any(|g| g.name == group.name)
+    {
+        return Err(ApiError::Conflict(format!(
+            "Permission group {} already exists",
+            group.name
+        )));
+    }
+    let non_members = state.find_non_members(&guild, &group.users).await?;
+    validate_permission_group(&guild, &group, &non_members)?;
+
+    let held = state.grantable_permissions(&config, &guild, &user).await?;
+    require_grantable(held, newly_granted(existing, &group))?;
+
+    let groups = config
+        .permission_groups
+        .iter()
+        .flatten()
+        .map(|g| {
+            if g.name == name {
+                group.clone()
+            } else {
+                g.clone()
+            }
+        })
+        .collect();
+    let reason = format!("Updated permission group {}", name);
+    let updated = state
+        .save_permission_groups(&guild, &config, groups, &user, &reason)
+        .await?;
+
+    group_response(StatusCode::OK, &updated, &group)
+}
+
+/// `DELETE /api/config/{id}/permission-groups/{name}` - remove a permission group.
+#[delete("/api/config/{id}/permission-groups/{name}")]
+#[instrument(skip(state, user, req), fields(user_id = %user.user_id))]
+pub async fn delete_permission_group(
+    state: web::Data<State>,
+    path: web::Path<(String, String)>,
+    req: HttpRequest,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let (id, name) = path.into_inner();
+    let id = parse_guild_id(&id)?;
+
+    let (guild, config) = state
+        .require_guild_permission(&user, &id, Permission::CONFIG_EDIT)
+        .await?;
+    require_if_match(&req, &config)?;
+    find_group(&config, &name)?;
+
+    let groups = config
+        .permission_groups
+        .iter()
+        .flatten()
+        .filter(|g| g.name != name)
+        .cloned()
+        .collect();
+    let reason = format!("Deleted permission group {}", name);
+    let updated = state
+        .save_permission_groups(&guild, &config, groups, &user, &reason)
+        .await?;
+
+    Ok(HttpResponse::NoContent()
+        .insert_header((header::ETAG, config_etag(&updated)?))
+        .finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn id(n: u64) -> Id {
+        Id::from_str(&n.to_string()).unwrap()
+    }
+
+    fn guild() -> Guild {
+        serde_json::from_value(json!({
+            "id": id(1),
+            "name": "guild",
+            "icon": null,
+            "owner_id": null,
+            "roles": [
+                { "id": id(10), "name": "mods", "position": 1, "permissions": 0, "managed": false },
+            ],
+            "member_count": null,
+            "approximate_member_count": null,
+        }))
+        .unwrap()
+    }
+
+    fn group(name: &str, roles: &[u64], users: &[u64]) -> Group {
+        Group {
+            name: name.to_string(),
+            roles: roles.iter().copied().map(id).collect(),
+            users: users.iter().copied().map(id).collect(),
+            permissions: Permission::MODERATION_KICK,
+        }
+    }
+
+    fn error_fields(group: &Group) -> Vec<String> {
+        match validate_permission_group(&guild(), group, &[id(99)]) {
+            Ok(()) => Vec::new(),
+            Err(ApiError::Validation(errors)) => errors.into_iter().map(|e| e.field).collect(),
+            Err(e) => panic!("unexpected error: {e}"),
+        }
+    }
+
+    #[test]
+    fn accepts_existing_roles_and_members() {
+        assert!(error_fields(&group("mods", &[10], &[20, 21])).is_empty());
+    }
+
+    #[test]
+    fn rejects_users_who_arent_members() {
+        assert_eq!(error_fields(&group("mods", &[10], &[20, 99])), ["/users/1"]);
+    }
+
+    #[test]
+    fn rejects_bad_names() {
+        assert_eq!(error_fields(&group(" ", &[], &[])), ["/name"]);
+        assert_eq!(error_fields(&group(" mods", &[], &[])), ["/name"]);
+    }
+
+    #[test]
+    fn new_members_are_granted_every_permission() {
+        let mut existing = group("mods", &[10], &[20]);
+        existing.permissions = Permission::MODERATION_KICK | Permission::MODERATION_BAN;
+
+        let mut updated = existing.clone();
+        updated.roles.push(id(11));
+        assert_eq!(newly_granted(&existing, &updated), existing.permissions);
+
+        let mut updated = existing.clone();
+        updated.roles.clear();
+        updated.users.push(id(21));
+        assert_eq!(newly_granted(&existing, &updated), existing.permissions);
+    }
+
+    #[test]
+    fn existing_members_are_granted_added_permissions() {
+        let existing = group("mods", &[10], &[20]);
+
+        let mut updated = existing.clone();
+        updated.name = "moderators".into();
+        updated.users.clear();
+        updated.permissions |= Permission::CONFIG_VIEW;
+        assert_eq!(newly_granted(&existing, &updated), Permission::CONFIG_VIEW);
+
+        updated.permissions = Permission::empty();
+        assert_eq!(newly_granted(&existing, &updated), Permission::empty());
+    }
+
+    #[test]
+    fn rejects_unknown_roles() {
+        assert_eq!(
+            error_fields(&group("mods", &[10, 11, 12], &[])),
+            ["/roles/1", "/roles/2"]
+        );
+    }
+}
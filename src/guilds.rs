@@ -5,7 +5,7 @@ use tracing::instrument;
 use bm_lib::discord::{Channel, Id, Role};
 use bm_lib::permissions::Permission;
 
-use crate::{auth::AuthenticatedUser, error::ApiError, State};
+use crate::{auth::AuthenticatedUser, error::ApiError, templates, State};
 
 #[derive(Debug, Serialize)]
 pub struct UserGuild {
@@ -30,6 +30,9 @@ pub struct UserGuild {
     pub moderation_enabled: bool,
     /// Whether music module is enabled.
     pub music_enabled: bool,
+    /// The guild has no config yet and the user can create one with
+    /// `PUT /api/config/{id}`.
+    pub needs_setup: bool,
 }
 
 /// `GET /api/guilds` - list guilds the authenticated user can view config for.
@@ -40,6 +43,11 @@ pub struct UserGuild {
 /// 2. For each guild, fetch guild + config from cache/DB (O(m) where m = guild count).
 /// 3. Resolve Discord + DB permissions for each guild using permission inheritance.
 /// 4. Return only guilds where user has CONFIG_VIEW permission (includes Discord admins).
+///  Guilds without a config are resolved against the default template and
+///  returned with `needs_setup` when the user could set them up (CONFIG_EDIT).
+///
+/// API keys only see the guild they were issued for, with their permissions
+/// narrowed to the key's scope.
 #[get("/api/me/guilds")]
 #[instrument(skip(state, user), fields(user_id = %user.user_id))]
 pub async fn get_guilds(
@@ -59,11 +67,20 @@ pub async fn get_guilds(
     // Check permissions for each guild the user is a member of.
     // This respects both Discord permissions (admin, etc.) and Black Mesa permission groups.
     for guild_id in &member_guild_ids {
+        if user
+            .api_key
+            .as_ref()
+            .is_some_and(|scope| scope.guild_id != *guild_id)
+        {
+            continue;
+        }
+
         let Some(guild) = state.get_guild(guild_id).await? else {
             continue;
         };
-        let Some(config) = state.get_config(guild_id).await? else {
-            continue;
+        let (config, needs_setup) = match state.get_config(guild_id).await? {
+            Some(config) => (config, false),
+            None => (templates::default_config(guild_id)?, true),
         };
 
         // Resolve the user's Discord roles in this guild for role name lookups.
@@ -108,31 +125,10 @@ pub async fn get_guilds(
             Err(_) => (0, 0),
         };
 
-        // Guild owner always has access
-        if guild.owner_id == Some(user.user_id) {
-            guilds.push(UserGuild {
-                id: guild_id.to_string(),
-                name: guild.name.to_string(),
-                icon: guild.icon.map(|s| s.to_string()),
-                permissions: Permission::all(),
-                owner: true,
-                highest_role,
-                permission_groups,
-                member_count: guild.member_count.or(guild.approximate_member_count),
-                infraction_count,
-                active_infraction_count,
-                automod_enabled: config.automod_enabled,
-                moderation_enabled: config.moderation_enabled,
-                music_enabled: config.music_enabled,
-            });
-            continue;
-        }
-
-        // Resolve full permissions (Discord + Black Mesa permission groups)
-        let perms = match state
-            .resolve_member_permissions(&config, &guild, &user.user_id)
-            .await
-        {
+        // Resolve full permissions (Discord + Black Mesa permission groups).
+        // The guild owner holds every permission, and API keys only what
+        // their scope allows.
+        let perms = match state.grantable_permissions(&config, &guild, &user).await {
             Ok(perms) => perms,
             Err(e) => {
                 tracing::warn!(guild_id = %guild_id, error = ?e, "Failed to resolve permissions");
@@ -140,8 +136,14 @@ pub async fn get_guilds(
             }
         };
 
-        // Only include guilds where user has CONFIG_VIEW permission
-        if !perms.has_permission(Permission::CONFIG_VIEW) {
+        // Only include guilds where user has CONFIG_VIEW permission, or could
+        // set up a guild that has no config yet.
+        let required = if needs_setup {
+            Permission::CONFIG_EDIT
+        } else {
+            Permission::CONFIG_VIEW
+        };
+        if !perms.has_permission(required) {
             continue;
         }
 
@@ -150,7 +152,7 @@ pub async fn get_guilds(
             name: guild.name.to_string(),
             icon: guild.icon.map(|s| s.to_string()),
             permissions: perms,
-            owner: false,
+            owner: guild.owner_id == Some(user.user_id),
             highest_role,
             permission_groups,
             member_count: guild.member_count.or(guild.approximate_member_count),
@@ -159,6 +161,7 @@ pub async fn get_guilds(
             automod_enabled: config.automod_enabled,
             moderation_enabled: config.moderation_enabled,
             music_enabled: config.music_enabled,
+            needs_setup,
         });
     }
 
@@ -179,23 +182,7 @@ pub async fn get_guild_channels(
         .require_guild_permission(&user, &guild_id, Permission::CONFIG_VIEW)
         .await?;
 
-    // Try cache first, fallback to Discord API if needed
-    let channels = match state.get_channels(&guild_id).await? {
-        Some(cached) => cached,
-        None => {
-            // Cache miss - fetch from Discord and update cache
-            let fetched = state
-                .bot
-                .get_guild_channels(&guild_id)
-                .await
-                .map_err(|e| ApiError::Internal(format!("Discord API error: {}", e)))?;
-
-            // Store in cache for future requests
-            state.set_channels(&guild_id, &fetched).await?;
-
-            fetched
-        }
-    };
+    let channels = state.guild_channels(&guild_id).await?;
 
     Ok(web::Json(channels))
 }
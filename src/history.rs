@@ -0,0 +1,392 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use bm_lib::{discord::Id, model::Config, permissions::Permission};
+use chrono::Utc;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{
+    api::{config_response, require_if_match},
+    auth::AuthenticatedUser,
+    error::ApiError,
+    State,
+};
+
+/// How many versions of a guild's config are kept.
+const CONFIG_HISTORY_LIMIT: usize = 100;
+
+/// Metadata for one saved version of a guild's config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigVersionInfo {
+    pub version: u64,
+    /// Who saved this version. `None` for the config as it was before history
+    /// was first recorded.
+    pub editor: Option<Id>,
+    /// Unix timestamp (seconds).
+    pub timestamp: i64,
+    /// What produced this version, e.g. a rollback.
+    pub reason: Option<String>,
+}
+
+/// A saved version of a guild's config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigVersion {
+    #[serde(flatten)]
+    pub info: ConfigVersionInfo,
+    pub config: Config,
+}
+
+/// Counter handing out a guild's config version numbers.
+#[inline]
+fn config_version_counter_key(guild_id: &Id) -> String {
+    format!("config_version_counter:{}", guild_id)
+}
+
+/// List of [`ConfigVersionInfo`] for a guild's kept versions.
+#[inline]
+fn config_history_key(guild_id: &Id) -> String {
+    format!("config_history:{}", guild_id)
+}
+
+/// Hash of a guild's kept [`ConfigVersion`]s, keyed by version number.
+#[inline]
+fn config_versions_key(guild_id: &Id) -> String {
+    format!("config_versions:{}", guild_id)
+}
+
+fn serialize<T: Serialize>(value: &T) -> Result<String, ApiError> {
+    serde_json::to_string(value)
+        .map_err(|e| ApiError::Internal(format!("Failed to serialize config version: {e}")))
+}
+
+fn deserialize<T: DeserializeOwned>(value: &str) -> Result<T, ApiError> {
+    serde_json::from_str(value)
+        .map_err(|e| ApiError::Internal(format!("Invalid config version record: {e}")))
+}
+
+/// Parse stored history entries, oldest first. Concurrent writes may append
+/// versions slightly out of order, so entries are sorted by version.
+fn parse_history(entries: &[String]) -> Result<Vec<ConfigVersionInfo>, ApiError> {
+    let mut history = entries
+        .iter()
+        .map(|entry| deserialize::<ConfigVersionInfo>(entry))
+        .collect::<Result<Vec<_>, _>>()?;
+    history.sort_by_key(|info| info.version);
+    Ok(history)
+}
+
+/// How many of the oldest versions to drop from a history of `len` versions
+/// to get back to [`CONFIG_HISTORY_LIMIT`].
+fn history_overflow(len: usize) -> usize {
+    len.saturating_sub(CONFIG_HISTORY_LIMIT)
+}
+
+/// The version numbers of stored history entries, as keys of the versions hash.
+fn version_fields(entries: &[String]) -> Result<Vec<String>, ApiError> {
+    Ok(parse_history(entries)?
+        .iter()
+        .map(|info| info.version.to_string())
+        .collect())
+}
+
+/// The stored history entry for `version`, if it's still kept.
+fn find_entry(entries: &[String], version: u64) -> Result<Option<&String>, ApiError> {
+    for entry in entries {
+        if deserialize::<ConfigVersionInfo>(entry)?.version == version {
+            return Ok(Some(entry));
+        }
+    }
+
+    Ok(None)
+}
+
+impl State {
+    /// Saved versions of a guild's config, oldest first.
+    #[instrument(skip(self))]
+    pub async fn get_config_history(
+        &self,
+        guild_id: &Id,
+    ) -> Result<Vec<ConfigVersionInfo>, ApiError> {
+        let entries = self.store.list_all(&config_history_key(guild_id)).await?;
+        parse_history(&entries)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_config_version(
+        &self,
+        guild_id: &Id,
+        version: u64,
+    ) -> Result<Option<ConfigVersion>, ApiError> {
+        self.store
+            .hash_get(&config_versions_key(guild_id), &version.to_string())
+            .await?
+            .map(|snapshot| deserialize(&snapshot))
+            .transpose()
+    }
+
+    /// Save `config` as the newest version, before it is written. `previous`
+    /// is the config it replaces; it is saved first if the guild has no
+    /// history yet, so the first edit can be rolled back too. If the write
+    /// then fails, [`State::discard_config_version`] takes the version back.
+    ///
+    /// Version numbers come from an atomic counter, so concurrent edits each
+    /// get their own version.
+    #[instrument(skip(self, previous, config))]
+    pub async fn record_config_version(
+        &self,
+        guild_id: &Id,
+        previous: Option<&Config>,
+        config: &Config,
+        editor: &Id,
+        reason: Option<&str>,
+    ) -> Result<u64, ApiError> {
+        let counter = config_version_counter_key(guild_id);
+        let mut version = self.store.increment(&counter).await?;
+
+        // Only the first write to a guild's history ever gets version 1.
+        if version == 1 {
+            if let Some(previous) = previous {
+                self.push_config_version(guild_id, version, previous, None, None)
+                    .await?;
+                version = self.store.increment(&counter).await?;
+            }
+        }
+
+        let len = self
+            .push_config_version(guild_id, version, config, Some(*editor), reason)
+            .await?;
+
+        let dropped = self
+            .store
+            .list_pop_front(&config_history_key(guild_id), history_overflow(len))
+            .await?;
+        self.store
+            .hash_delete(&config_versions_key(guild_id), &version_fields(&dropped)?)
+            .await?;
+
+        Ok(version)
+    }
+
+    /// Remove a version recorded for a config write that then failed.
+    #[instrument(skip(self))]
+    pub async fn discard_config_version(
+        &self,
+        guild_id: &Id,
+        version: u64,
+    ) -> Result<(), ApiError> {
+        let history = config_history_key(guild_id);
+        let entries = self.store.list_all(&history).await?;
+        let entry = find_entry(&entries, version)?.cloned().unwrap_or_default();
+
+        Ok(self
+            .store
+            .delete_and_remove(
+                &config_versions_key(guild_id),
+                &version.to_string(),
+                &history,
+                &entry,
+            )
+            .await?)
+    }
+
+    /// Store one version and append it to the history. Returns how many
+    /// versions the history now holds.
+    async fn push_config_version(
+        &self,
+        guild_id: &Id,
+        version: u64,
+        config: &Config,
+        editor: Option<Id>,
+        reason: Option<&str>,
+    ) -> Result<usize, ApiError> {
+        let info = ConfigVersionInfo {
+            version,
+            editor,
+            timestamp: Utc::now().timestamp(),
+            reason: reason.map(str::to_string),
+        };
+        let snapshot = ConfigVersion {
+            info: info.clone(),
+            config: config.clone(),
+        };
+
+        Ok(self
+            .store
+            .insert_and_append(
+                &config_versions_key(guild_id),
+                &version.to_string(),
+                &serialize(&snapshot)?,
+                &config_history_key(guild_id),
+                &serialize(&info)?,
+            )
+            .await?)
+    }
+}
+
+/// `GET /api/config/{id}/history` - saved versions of a guild's config, newest first.
+#[get("/api/config/{id}/history")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn get_config_history(
+    state: web::Data<State>,
+    id: web::Path<String>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Vec<ConfigVersionInfo>>, ApiError> {
+    let id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".to_string()))?;
+
+    state
+        .require_guild_permission(&user, &id, Permission::CONFIG_VIEW)
+        .await?;
+
+    let mut history = state.get_config_history(&id).await?;
+    history.reverse();
+
+    Ok(web::Json(history))
+}
+
+/// `GET /api/config/{id}/history/{version}` - one saved version, including the config.
+#[get("/api/config/{id}/history/{version}")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn get_config_version(
+    state: web::Data<State>,
+    path: web::Path<(String, u64)>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<ConfigVersion>, ApiError> {
+    let (id, version) = path.into_inner();
+    let id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".to_string()))?;
+
+    state
+        .require_guild_permission(&user, &id, Permission::CONFIG_VIEW)
+        .await?;
+
+    let snapshot = state
+        .get_config_version(&id, version)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Config version not found".into()))?;
+
+    Ok(web::Json(snapshot))
+}
+
+/// `POST /api/config/{id}/rollback/{version}` - restore a saved version. The
+/// restored config is saved as a new version, so a rollback can be undone too.
+///
+/// The version is validated against the guild as it is now, like any other
+/// config write, and `If-Match` must name the current config.
+#[post("/api/config/{id}/rollback/{version}")]
+#[instrument(skip(state, user, req), fields(user_id = %user.user_id))]
+pub async fn rollback_config(
+    state: web::Data<State>,
+    path: web::Path<(String, u64)>,
+    req: HttpRequest,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let (id, version) = path.into_inner();
+    let id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".to_string()))?;
+
+    let (guild, current) = state
+        .require_guild_permission(&user, &id, Permission::CONFIG_EDIT)
+        .await?;
+    require_if_match(&req, &current)?;
+
+    let snapshot = state
+        .get_config_version(&id, version)
+        .await?
+        .ok_or_else(|| ApiError::NotFound("Config version not found".into()))?;
+
+    // Roles and channels the version refers to may have been deleted since.
+    state.validate_config(&snapshot.config, &guild).await?;
+    state
+        .check_escalation(&guild, &current, &snapshot.config, &user)
+        .await?;
+
+    let reason = format!("Rollback to version {}", version);
+    let updated = state
+        .update_config(
+            &id,
+            &current,
+            &snapshot.config,
+            &user.user_id,
+            Some(&reason),
+        )
+        .await?;
+
+    config_response(&updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(version: u64) -> String {
+        serialize(&ConfigVersionInfo {
+            version,
+            editor: Some(Id::from_str("1").unwrap()),
+            timestamp: 1_700_000_000 + version as i64,
+            reason: None,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn history_is_sorted_by_version() {
+        let history = parse_history(&[entry(3), entry(1), entry(2)]).unwrap();
+
+        let versions: Vec<u64> = history.iter().map(|info| info.version).collect();
+        assert_eq!(versions, [1, 2, 3]);
+    }
+
+    #[test]
+    fn history_is_trimmed_to_the_limit() {
+        assert_eq!(history_overflow(0), 0);
+        assert_eq!(history_overflow(CONFIG_HISTORY_LIMIT), 0);
+        assert_eq!(history_overflow(CONFIG_HISTORY_LIMIT + 1), 1);
+        assert_eq!(history_overflow(CONFIG_HISTORY_LIMIT + 3), 3);
+    }
+
+    #[test]
+    fn trimming_drops_the_oldest_versions() {
+        let mut entries: Vec<String> = (1..=CONFIG_HISTORY_LIMIT as u64 + 2).map(entry).collect();
+        let dropped: Vec<String> = entries.drain(..history_overflow(entries.len())).collect();
+
+        assert_eq!(version_fields(&dropped).unwrap(), ["1", "2"]);
+
+        let kept = parse_history(&entries).unwrap();
+        assert_eq!(kept.len(), CONFIG_HISTORY_LIMIT);
+        assert_eq!(kept.first().unwrap().version, 3);
+        assert_eq!(
+            kept.last().unwrap().version,
+            CONFIG_HISTORY_LIMIT as u64 + 2
+        );
+    }
+
+    #[test]
+    fn entries_are_found_by_version() {
+        let entries = [entry(4), entry(5), entry(6)];
+
+        assert_eq!(find_entry(&entries, 5).unwrap(), Some(&entries[1]));
+        assert_eq!(find_entry(&entries, 7).unwrap(), None);
+    }
+
+    #[test]
+    fn empty_history_parses() {
+        assert!(parse_history(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn corrupt_history_entries_are_an_error() {
+        assert!(parse_history(&[entry(1), "not json".into()]).is_err());
+    }
+
+    #[test]
+    fn version_info_without_editor_round_trips() {
+        let info = ConfigVersionInfo {
+            version: 1,
+            editor: None,
+            timestamp: 0,
+            reason: Some("Rollback to version 4".into()),
+        };
+        let parsed: ConfigVersionInfo = deserialize(&serialize(&info).unwrap()).unwrap();
+
+        assert_eq!(parsed.version, 1);
+        assert!(parsed.editor.is_none());
+        assert_eq!(parsed.reason.as_deref(), Some("Rollback to version 4"));
+    }
+}
@@ -1,45 +1,252 @@
-use chrono::{Duration, Utc};
-use jsonwebtoken::{encode, errors::Error, EncodingKey, Header};
+use std::{
+    collections::HashMap,
+    fs,
+    io::{Error as IoError, ErrorKind, Result as IoResult},
+    str::FromStr,
+    time::Duration,
+};
+
+use jsonwebtoken::{
+    decode, decode_header, encode,
+    errors::Error,
+    jwk::{AlgorithmParameters, JwkSet},
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
 use serde::{Deserialize, Serialize};
 
+use crate::{config::Settings, sessions::Session};
+
+/// Dashboard token claims. Discord credentials are never embedded here; they
+/// live encrypted in the [`Session`] record referenced by `jti`.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub sub: String,
-    pub discord_token: String,
-    pub discord_refresh: String,
-    pub discord_token_type: String,
-    pub discord_scope: String,
-    pub discord_expires_in: u64,
+    /// Session ID; see [`Session`].
+    pub jti: String,
+    pub iat: i64,
     pub exp: i64,
 }
 
-pub fn create_token(
-    user_id: &str,
-    discord_token: &str,
-    discord_refresh: &str,
-    discord_token_type: &str,
-    discord_scope: &str,
-    expires_in: u64,
-    jwt_secret: &str,
-) -> Result<String, Error> {
-    let expiration = Utc::now()
-        .checked_add_signed(Duration::seconds(expires_in as i64))
-        .unwrap_or_else(Utc::now)
-        .timestamp();
+/// Token signing and verification keys.
+///
+/// With `HS256` tokens are signed and verified with the shared `JWT_SECRET`.
+/// With `RS256`/`EdDSA` tokens are signed with a private key and carry its
+/// `kid`; any key in the configured public JWKS verifies them, so keys can be
+/// rotated by publishing the new key before switching the signing key, and
+/// other services can verify tokens via `/.well-known/jwks.json`.
+pub struct JwtKeys {
+    algorithm: Algorithm,
+    kid: Option<String>,
+    encoding: EncodingKey,
+    /// Verification keys by `kid`. Empty for `HS256`.
+    verifying: HashMap<String, (DecodingKey, Algorithm)>,
+    /// Fallback verification key for `HS256`.
+    secret: Option<DecodingKey>,
+    /// Public keys advertised to other services.
+    jwks: JwkSet,
+}
+
+impl JwtKeys {
+    pub fn from_settings(settings: &Settings) -> IoResult<Self> {
+        let invalid = |msg: String| IoError::new(ErrorKind::InvalidInput, msg);
+
+        let algorithm = Algorithm::from_str(&settings.jwt_algorithm).map_err(|_| {
+            invalid(format!(
+                "Unsupported JWT_ALGORITHM: {}",
+                settings.jwt_algorithm
+            ))
+        })?;
+
+        if algorithm == Algorithm::HS256 {
+            let secret = settings
+                .jwt_secret
+                .as_deref()
+                .ok_or_else(|| invalid("JWT_SECRET is required for HS256".into()))?;
+
+            return Ok(Self::from_secret(secret.as_bytes()));
+        }
+
+        let (Some(key_path), Some(kid), Some(jwks_path)) = (
+            settings.jwt_private_key_file.as_deref(),
+            settings.jwt_key_id.as_deref(),
+            settings.jwt_jwks_file.as_deref(),
+        ) else {
+            return Err(invalid(format!(
+                "JWT_PRIVATE_KEY_FILE, JWT_KEY_ID and JWT_JWKS_FILE are required for {:?}",
+                algorithm
+            )));
+        };
+
+        let pem = fs::read(key_path)?;
+        let encoding = match algorithm {
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(&pem),
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(&pem),
+            other => return Err(invalid(format!("Unsupported JWT_ALGORITHM: {:?}", other))),
+        }
+        .map_err(|e| invalid(format!("Invalid JWT_PRIVATE_KEY_FILE: {e}")))?;
+
+        let jwks: JwkSet = serde_json::from_slice(&fs::read(jwks_path)?)
+            .map_err(|e| invalid(format!("Invalid JWT_JWKS_FILE: {e}")))?;
+
+        let mut verifying = HashMap::new();
+        for jwk in &jwks.keys {
+            let key_id = jwk
+                .common
+                .key_id
+                .clone()
+                .ok_or_else(|| invalid("Every key in JWT_JWKS_FILE needs a kid".into()))?;
+            if matches!(jwk.algorithm, AlgorithmParameters::OctetKey(_)) {
+                return Err(invalid(format!(
+                    "Key {key_id} is symmetric; JWKS must only publish public keys"
+                )));
+            }
+            let alg = jwk
+                .common
+                .key_algorithm
+                .and_then(|alg| Algorithm::from_str(&alg.to_string()).ok())
+                .ok_or_else(|| invalid(format!("Key {key_id} has no supported alg")))?;
+            let key = DecodingKey::from_jwk(jwk)
+                .map_err(|e| invalid(format!("Key {key_id} is invalid: {e}")))?;
+            verifying.insert(key_id, (key, alg));
+        }
+
+        if !verifying.contains_key(kid) {
+            return Err(invalid(format!("JWT_KEY_ID {kid} is not in JWT_JWKS_FILE")));
+        }
+
+        Ok(Self {
+            algorithm,
+            kid: Some(kid.to_string()),
+            encoding,
+            verifying,
+            secret: None,
+            jwks,
+        })
+    }
+
+    /// `HS256` keys from a shared secret.
+    fn from_secret(secret: &[u8]) -> Self {
+        Self {
+            algorithm: Algorithm::HS256,
+            kid: None,
+            encoding: EncodingKey::from_secret(secret),
+            verifying: HashMap::new(),
+            secret: Some(DecodingKey::from_secret(secret)),
+            jwks: JwkSet { keys: Vec::new() },
+        }
+    }
+
+    /// Public verification keys; empty when using a shared secret.
+    pub fn jwks(&self) -> &JwkSet {
+        &self.jwks
+    }
+
+    pub fn sign(&self, claims: &Claims) -> Result<String, Error> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.kid.clone();
+        encode(&header, claims, &self.encoding)
+    }
+
+    /// Verify a token's signature and expiry, picking the key by its `kid`.
+    pub fn verify(&self, token: &str) -> Result<Claims, Error> {
+        let header = decode_header(token)?;
 
+        let (key, algorithm) = match (&header.kid, &self.secret) {
+            (Some(kid), _) => self
+                .verifying
+                .get(kid)
+                .map(|(key, alg)| (key, *alg))
+                .ok_or(jsonwebtoken::errors::ErrorKind::InvalidToken)?,
+            (None, Some(secret)) => (secret, Algorithm::HS256),
+            (None, None) => return Err(jsonwebtoken::errors::ErrorKind::InvalidToken.into()),
+        };
+
+        decode::<Claims>(token, key, &Validation::new(algorithm)).map(|data| data.claims)
+    }
+}
+
+/// Sign a short-lived access token for `session`, valid for `ttl` or until the
+/// session itself expires, whichever comes first.
+pub fn create_token(session: &Session, ttl: Duration, keys: &JwtKeys) -> Result<String, Error> {
+    let now = chrono::Utc::now().timestamp();
     let claims = Claims {
-        sub: user_id.to_string(),
-        discord_token: discord_token.to_string(),
-        discord_refresh: discord_refresh.to_string(),
-        discord_token_type: discord_token_type.to_string(),
-        discord_scope: discord_scope.to_string(),
-        discord_expires_in: expires_in,
-        exp: expiration,
+        sub: session.user_id.to_string(),
+        jti: session.id.clone(),
+        iat: now,
+        exp: (now + ttl.as_secs() as i64).min(session.expires_at),
     };
 
-    encode(
-        &Header::default(),
-        &claims,
-        &EncodingKey::from_secret(jwt_secret.as_bytes()),
-    )
+    keys.sign(&claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+
+    use super::*;
+
+    fn session(expires_in: i64) -> Session {
+        let now = Utc::now().timestamp();
+        Session {
+            id: "session".into(),
+            user_id: "42".parse().unwrap(),
+            created_at: now,
+            expires_at: now + expires_in,
+            discord: String::new(),
+        }
+    }
+
+    fn claims(exp: i64) -> Claims {
+        Claims {
+            sub: "42".into(),
+            jti: "session".into(),
+            iat: Utc::now().timestamp(),
+            exp,
+        }
+    }
+
+    #[test]
+    fn signed_tokens_verify() {
+        let keys = JwtKeys::from_secret(b"secret");
+        let token = create_token(&session(3600), Duration::from_secs(900), &keys).unwrap();
+
+        let claims = keys.verify(&token).unwrap();
+        assert_eq!(claims.sub, "42");
+        assert_eq!(claims.jti, "session");
+        assert_eq!(claims.exp - claims.iat, 900);
+    }
+
+    #[test]
+    fn tokens_expire_with_their_session() {
+        let keys = JwtKeys::from_secret(b"secret");
+        let session = session(300);
+        let token = create_token(&session, Duration::from_secs(900), &keys).unwrap();
+
+        assert_eq!(keys.verify(&token).unwrap().exp, session.expires_at);
+    }
+
+    #[test]
+    fn rejects_other_secrets_and_expired_tokens() {
+        let keys = JwtKeys::from_secret(b"secret");
+        let other = JwtKeys::from_secret(b"other").sign(&claims(Utc::now().timestamp() + 60));
+        assert!(keys.verify(&other.unwrap()).is_err());
+
+        let expired = keys.sign(&claims(Utc::now().timestamp() - 3600)).unwrap();
+        assert!(keys.verify(&expired).is_err());
+    }
+
+    #[test]
+    fn rejects_unknown_kids() {
+        let keys = JwtKeys::from_secret(b"secret");
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("unknown".into());
+        let token = encode(
+            &header,
+            &claims(Utc::now().timestamp() + 60),
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+
+        assert!(keys.verify(&token).is_err());
+    }
 }
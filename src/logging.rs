@@ -9,6 +9,29 @@ use tracing::instrument;
 
 use crate::{auth::AuthenticatedUser, error::ApiError, State};
 
+impl State {
+    /// Make a guild's log configs exactly `logs`: upsert each and delete any
+    /// event not among them.
+    #[instrument(skip(self, logs))]
+    pub async fn replace_log_configs(
+        &self,
+        guild_id: &Id,
+        logs: &[LogConfig],
+    ) -> Result<Vec<LogConfig>, ApiError> {
+        let current = self.db.get_log_configs(guild_id).await?;
+        let saved = self.db.bulk_upsert_log_configs(guild_id, logs).await?;
+
+        for stale in current
+            .iter()
+            .filter(|current| !logs.iter().any(|log| log.event == current.event))
+        {
+            self.db.delete_log_config(guild_id, &stale.event).await?;
+        }
+
+        Ok(saved)
+    }
+}
+
 /// `GET /api/logging/{guild_id}` - list all log configs for a guild.
 #[get("/api/logging/{guild_id}")]
 #[instrument(skip(state, user), fields(user_id = %user.user_id))]
@@ -1,25 +1,46 @@
 mod api;
+mod api_keys;
 mod auth;
 mod config;
+mod cookies;
+mod copy;
+mod crypto;
 mod data;
+mod diff;
 mod discord;
 mod error;
+mod events;
+mod export;
+mod groups;
 mod guilds;
+mod history;
 mod infractions;
 mod jwt;
 mod logging;
 mod permissions;
+mod sessions;
+mod staff;
+mod store;
 mod telemetry;
+mod templates;
+mod validation;
+
+use std::time::Duration;
 
 use actix_cors::Cors;
-use actix_web::{get, web::Data, App, HttpServer};
+use actix_web::{get, http::header::ETAG, middleware::from_fn, web::Data, App, HttpServer};
 use bm_lib::{
     cache::{Cache, RedisCache},
     db::Database,
     discord::DiscordRestClient,
 };
 use config::Settings;
+use crypto::SecretBox;
 use discord::RestClient;
+use events::EventPublisher;
+use jwt::JwtKeys;
+use staff::StaffList;
+use store::Store;
 use tracing_actix_web::TracingLogger;
 
 const SERVICE_NAME: &str = concat!(env!("CARGO_PKG_NAME"), " v", env!("CARGO_PKG_VERSION"));
@@ -28,14 +49,24 @@ pub struct State {
     pub db: Database,
     pub cache: Cache<RedisCache>,
     pub bot_cache: Cache<RedisCache>,
+    pub store: Store,
     pub rest: RestClient,
     pub bot: DiscordRestClient,
-    pub jwt_secret: String,
+    pub events: EventPublisher,
+    pub jwt_keys: JwtKeys,
+    pub oauth_allowed_redirect_uris: Vec<String>,
+    pub access_token_ttl: Duration,
+    pub refresh_token_ttl: Duration,
+    pub secrets: SecretBox,
+    pub cookie_auth: bool,
+    pub staff: StaffList,
+    /// Hashes of the configured service tokens.
+    pub service_token_hashes: Vec<String>,
 }
 
 impl State {
-    pub async fn new(settings: &Settings) -> Self {
-        Self {
+    pub async fn new(settings: &Settings) -> std::io::Result<Self> {
+        Ok(Self {
             db: {
                 let db = Database::connect(settings.database_url.clone())
                     .await
@@ -58,14 +89,32 @@ impl State {
                 .await
                 .expect("Failed to connect to bot Redis namespace"),
             ),
+            store: Store::connect(&settings.redis_uri, &settings.redis_prefix)
+                .await
+                .expect("Failed to connect to Redis"),
             rest: RestClient::new(
                 settings.discord_client_id.clone(),
                 settings.discord_client_secret.clone(),
                 settings.discord_redirect_uri.clone(),
+                settings.discord_bot_token.clone(),
             ),
             bot: DiscordRestClient::new(&settings.discord_bot_token),
-            jwt_secret: settings.jwt_secret.clone(),
-        }
+            events: EventPublisher::connect(&settings.redis_uri, &settings.bot_redis_prefix)
+                .await
+                .expect("Failed to connect to Redis for pub/sub"),
+            jwt_keys: JwtKeys::from_settings(settings)?,
+            oauth_allowed_redirect_uris: settings.oauth_allowed_redirect_uris.clone(),
+            access_token_ttl: Duration::from_secs(settings.access_token_ttl),
+            refresh_token_ttl: Duration::from_secs(settings.refresh_token_ttl),
+            secrets: SecretBox::from_base64_key(&settings.session_encryption_key)?,
+            cookie_auth: settings.cookie_auth,
+            staff: StaffList::from_settings(settings)?,
+            service_token_hashes: settings
+                .service_tokens
+                .iter()
+                .map(|token| crypto::hash_token(token))
+                .collect(),
+        })
     }
 }
 
@@ -80,25 +129,83 @@ async fn main() -> std::io::Result<()> {
         settings.otlp_organization.as_deref(),
     );
 
-    let state = Data::new(State::new(&settings).await);
+    let state = Data::new(State::new(&settings).await?);
+
+    // Credentialed (cookie) requests can't use a wildcard origin, so in cookie
+    // mode only the dashboards we redirect logins to may call the API.
+    let cookie_origins: Vec<String> = settings
+        .oauth_allowed_redirect_uris
+        .iter()
+        .filter_map(|uri| reqwest::Url::parse(uri).ok())
+        .map(|url| url.origin().ascii_serialization())
+        .collect();
+    let cookie_auth = settings.cookie_auth;
 
     HttpServer::new(move || {
+        let cors = if cookie_auth {
+            cookie_origins
+                .iter()
+                .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+                .allow_any_method()
+                .allow_any_header()
+                .expose_headers([ETAG.as_str(), staff::STAFF_ACCESS_HEADER])
+                .supports_credentials()
+        } else {
+            Cors::default()
+                .allow_any_origin()
+                .allow_any_method()
+                .allow_any_header()
+                .expose_headers([ETAG.as_str(), staff::STAFF_ACCESS_HEADER])
+        };
+
         App::new()
-            .wrap(
-                Cors::default()
-                    .allow_any_origin()
-                    .allow_any_method()
-                    .allow_any_header(),
-            )
+            .wrap(cors)
+            .wrap(from_fn(staff::staff_access_header))
             .wrap(TracingLogger::default())
             .service(healthz)
+            .service(auth::jwks)
             // Auth
+            .service(auth::oauth_discord_authorize)
             .service(auth::oauth_discord)
-            .service(auth::refresh_token)
+            .service(auth::refresh)
             .service(auth::get_me)
+            .service(auth::logout)
+            .service(auth::logout_all)
+            .service(auth::introspect)
             // Guild config
             .service(api::get_config)
             .service(api::post_config)
+            .service(api::patch_config)
+            .service(api::init_config)
+            .service(history::get_config_history)
+            .service(history::get_config_version)
+            .service(history::rollback_config)
+            .service(diff::get_config_diff)
+            .service(diff::preview_config_diff)
+            .service(export::export_config)
+            .service(export::import_config)
+            .service(copy::copy_config)
+            .service(templates::preview_template)
+            .service(templates::apply_template_to_config)
+            .service(groups::list_permission_groups)
+            .service(groups::get_permission_group)
+            .service(groups::create_permission_group)
+            .service(groups::update_permission_group)
+            .service(groups::delete_permission_group)
+            // Config templates
+            .service(templates::list_templates)
+            .service(templates::save_template)
+            .service(templates::delete_template)
+            // API keys
+            .service(api_keys::list_api_keys)
+            .service(api_keys::create_api_key)
+            .service(api_keys::revoke_api_key)
+            // Staff
+            .service(staff::list_staff_access)
+            // Permissions
+            .service(permissions::explain_permissions)
+            .service(permissions::simulate_permissions)
+            .service(permissions::audit_permissions)
             // Guilds
             .service(guilds::get_guilds)
             .service(guilds::get_guild_channels)
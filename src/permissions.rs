@@ -1,7 +1,173 @@
-use bm_lib::{discord::Guild, model::Config, permissions::Permission};
+use std::collections::HashMap;
+
+use actix_web::{get, post, web};
+use bm_lib::{
+    discord::{Guild, Id},
+    model::{Config, Group},
+    permissions::Permission,
+};
+use serde::{Deserialize, Serialize};
 use tracing::instrument;
 
-use crate::{auth::AuthenticatedUser, error::ApiError, State};
+use crate::{auth::AuthenticatedUser, error::ApiError, staff::StaffAccess, State};
+
+/// A role or user that config can grant permissions to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Grantee {
+    Role(Id),
+    User(Id),
+}
+
+/// Permissions `config` grants each role and user through permission groups
+/// and Discord permissions. Roles are only granted anything when the config
+/// inherits Discord permissions.
+fn config_grants(config: &Config, guild: &Guild) -> HashMap<Grantee, Permission> {
+    let mut grants: HashMap<Grantee, Permission> = HashMap::new();
+
+    if config.inherit_discord_perms {
+        for role in &guild.roles {
+            *grants
+                .entry(Grantee::Role(role.id))
+                .or_insert(Permission::empty()) |=
+                Permission::from_discord_permissions(&guild.roles, &[role.id]);
+        }
+    }
+
+    for group in config.permission_groups.iter().flatten() {
+        let roles = group.roles.iter().filter(|_| config.inherit_discord_perms);
+        let grantees = roles
+            .map(|role| Grantee::Role(*role))
+            .chain(group.users.iter().map(|user| Grantee::User(*user)));
+        for grantee in grantees {
+            *grants.entry(grantee).or_insert(Permission::empty()) |= group.permissions;
+        }
+    }
+
+    grants
+}
+
+/// Whether `user` may change config without the escalation check: the guild
+/// owner, unless acting through an API key.
+fn escalation_exempt(guild: &Guild, user: &AuthenticatedUser) -> bool {
+    guild.owner_id == Some(user.user_id) && user.api_key.is_none()
+}
+
+/// Describe each role and user that `after` grants permissions outside `held`
+/// which `before` didn't already grant them, sorted.
+fn escalations(guild: &Guild, before: &Config, after: &Config, held: Permission) -> Vec<String> {
+    let previous = config_grants(before, guild);
+
+    let mut escalations: Vec<String> = config_grants(after, guild)
+        .into_iter()
+        .filter_map(|(grantee, granted)| {
+            let gained = granted
+                - previous
+                    .get(&grantee)
+                    .copied()
+                    .unwrap_or(Permission::empty());
+            let missing = gained - held;
+            if missing.is_empty() {
+                return None;
+            }
+
+            let who = match grantee {
+                Grantee::Role(id) => match guild.roles.iter().find(|r| r.id == id) {
+                    Some(role) => format!("role {} ({})", role.name, id),
+                    None => format!("role {}", id),
+                },
+                Grantee::User(id) => format!("user {}", id),
+            };
+            Some(format!("{} would gain {:?}", who, missing))
+        })
+        .collect();
+
+    escalations.sort();
+    escalations
+}
+
+/// How a user's permissions in a guild were resolved.
+#[derive(Debug, Clone, Serialize)]
+pub struct PermissionBreakdown {
+    /// The user's Discord roles. Only looked up when the config inherits
+    /// Discord permissions; `None` otherwise or if the member isn't cached.
+    pub member_roles: Option<Vec<Id>>,
+    /// Permissions from the user's Discord roles.
+    pub discord: Permission,
+    /// Permission groups the user is in.
+    pub groups: Vec<GroupMatch>,
+    /// Union of the Discord and group permissions.
+    pub permissions: Permission,
+}
+
+/// A permission group a user is in, and why.
+#[derive(Debug, Clone, Serialize)]
+pub struct GroupMatch {
+    pub name: String,
+    /// The user is listed in the group directly.
+    pub by_user: bool,
+    /// The user's roles the group includes.
+    pub by_roles: Vec<Id>,
+    pub permissions: Permission,
+}
+
+/// The rules behind [`State::resolve_member_permissions`], given the user's
+/// roles. Roles only count, for Discord permissions and for group membership,
+/// when the config inherits Discord permissions. Without a `user_id`, this
+/// resolves a hypothetical member with just `member_roles`.
+pub fn resolve_permissions(
+    config: &Config,
+    guild: &Guild,
+    user_id: Option<&Id>,
+    member_roles: Option<&[Id]>,
+) -> PermissionBreakdown {
+    let member_roles = member_roles.filter(|_| config.inherit_discord_perms);
+
+    let discord = match member_roles {
+        Some(roles) => Permission::from_discord_permissions(&guild.roles, roles),
+        None => Permission::empty(),
+    };
+
+    let mut permissions = discord;
+    let mut groups = Vec::new();
+    for group in config.permission_groups.iter().flatten() {
+        let by_user = user_id.is_some_and(|user_id| group.users.contains(user_id));
+        let by_roles: Vec<Id> = member_roles
+            .map(|roles| {
+                group
+                    .roles
+                    .iter()
+                    .filter(|r| roles.contains(r))
+                    .copied()
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        if by_user || !by_roles.is_empty() {
+            permissions |= group.permissions;
+            groups.push(GroupMatch {
+                name: group.name.clone(),
+                by_user,
+                by_roles,
+                permissions: group.permissions,
+            });
+        }
+    }
+
+    PermissionBreakdown {
+        member_roles: member_roles.map(<[Id]>::to_vec),
+        discord,
+        groups,
+        permissions,
+    }
+}
+
+/// Names of the flags set in `perms`.
+pub fn flag_names(perms: Permission) -> Vec<String> {
+    perms
+        .iter_names()
+        .map(|(name, _)| name.to_string())
+        .collect()
+}
 
 impl State {
     /// Compute the effective [`Permission`] for a user in a guild.
@@ -22,28 +188,89 @@ impl State {
             None
         };
 
-        let mut perms = if let Some(roles) = &member_roles {
-            Permission::from_discord_permissions(&guild.roles, roles)
-        } else {
-            Permission::empty()
-        };
+        Ok(resolve_permissions(config, guild, Some(user_id), member_roles.as_deref()).permissions)
+    }
 
-        if let Some(groups) = &config.permission_groups {
-            for group in groups {
-                let in_group = group.users.contains(user_id)
-                    || member_roles
-                        .as_ref()
-                        .map(|roles| group.roles.iter().any(|r| roles.contains(r)))
-                        .unwrap_or(false);
-                if in_group {
-                    perms |= group.permissions;
-                }
-            }
+    /// Like [`State::resolve_member_permissions`], but the guild owner holds
+    /// every permission.
+    pub async fn effective_permissions(
+        &self,
+        config: &Config,
+        guild: &Guild,
+        user_id: &bm_lib::discord::Id,
+    ) -> Result<Permission, ApiError> {
+        if guild.owner_id == Some(*user_id) {
+            return Ok(Permission::all());
+        }
+
+        self.resolve_member_permissions(config, guild, user_id)
+            .await
+    }
+
+    /// Whether the user owns the guild or the bot has seen them in it.
+    pub async fn is_guild_member(&self, guild: &Guild, user_id: &Id) -> Result<bool, ApiError> {
+        if guild.owner_id == Some(*user_id) {
+            return Ok(true);
+        }
+
+        Ok(self.get_member_guilds(user_id).await?.contains(&guild.id))
+    }
+
+    /// The permissions `user` can hand out in a guild: their effective
+    /// permissions, narrowed to the key's scope for API keys. A key holds
+    /// nothing outside its own guild.
+    pub async fn grantable_permissions(
+        &self,
+        config: &Config,
+        guild: &Guild,
+        user: &AuthenticatedUser,
+    ) -> Result<Permission, ApiError> {
+        if user
+            .api_key
+            .as_ref()
+            .is_some_and(|scope| scope.guild_id != guild.id)
+        {
+            return Ok(Permission::empty());
+        }
+
+        let mut perms = self
+            .effective_permissions(config, guild, &user.user_id)
+            .await?;
+        if let Some(scope) = &user.api_key {
+            perms &= scope.permissions;
         }
 
         Ok(perms)
     }
 
+    /// Reject a config change that would give any role or user permissions
+    /// the editor doesn't hold themselves, e.g. adding themselves to a group
+    /// with more permissions than their own. The guild owner is exempt, unless
+    /// acting through an API key.
+    #[instrument(skip_all, fields(guild_id = %guild.id, user_id = %user.user_id))]
+    pub async fn check_escalation(
+        &self,
+        guild: &Guild,
+        before: &Config,
+        after: &Config,
+        user: &AuthenticatedUser,
+    ) -> Result<(), ApiError> {
+        if escalation_exempt(guild, user) {
+            return Ok(());
+        }
+
+        let held = self.grantable_permissions(before, guild, user).await?;
+        let escalations = escalations(guild, before, after, held);
+        if escalations.is_empty() {
+            return Ok(());
+        }
+
+        Err(ApiError::Forbidden(format!(
+            "This change grants permissions you don't hold yourself: {}",
+            escalations.join("; ")
+        )))
+    }
+
     #[instrument(skip(self, config, user), fields(guild_id = %config.id, user_id = %user.user_id))]
     pub async fn check_permission(
         &self,
@@ -56,6 +283,14 @@ impl State {
             return Ok(false);
         };
 
+        // API keys only work in their own guild, and only for what they were granted.
+        if let Some(scope) = &user.api_key {
+            if scope.guild_id != guild.id || !scope.permissions.has_permission(perm) {
+                tracing::debug!(key_id = %scope.key_id, "Permission outside API key scope");
+                return Ok(false);
+            }
+        }
+
         // Guild owner always bypasses permission checks.
         if guild.owner_id == Some(user.user_id) {
             tracing::debug!("User is guild owner, permission granted");
@@ -70,6 +305,20 @@ impl State {
             return Ok(true);
         }
 
+        // Bot staff may act in any guild. API keys never carry staff access.
+        if user.api_key.is_none() {
+            if let Some(access) = self
+                .staff
+                .access(&user.user_id)
+                .filter(|access| access.allows(perm))
+            {
+                self.record_staff_access(&user.user_id, &guild.id, access, perm)
+                    .await?;
+                user.staff_access.mark(access);
+                return Ok(true);
+            }
+        }
+
         tracing::debug!("Permission check failed");
         Ok(false)
     }
@@ -102,3 +351,640 @@ impl State {
         Ok((guild, config))
     }
 }
+
+/// Reject granting any permission in `granted` that isn't in `held`.
+pub fn require_grantable(held: Permission, granted: Permission) -> Result<(), ApiError> {
+    let missing = granted - held;
+    if !missing.is_empty() {
+        return Err(ApiError::Forbidden(format!(
+            "Cannot grant permissions you don't hold yourself: {:?}",
+            missing
+        )));
+    }
+
+    Ok(())
+}
+
+/// Why a user has the permissions they have in a guild.
+#[derive(Debug, Serialize)]
+pub struct PermissionExplanation {
+    pub user_id: Id,
+    /// The guild owner holds every permission regardless of the rest.
+    pub owner: bool,
+    pub inherit_discord_perms: bool,
+    #[serde(flatten)]
+    pub breakdown: PermissionBreakdown,
+    /// Final permissions, after the owner bypass.
+    pub effective: Permission,
+    /// Names of the flags in `effective`.
+    pub flags: Vec<String>,
+    /// Bot staff access the user has on top, if any.
+    pub staff_access: Option<StaffAccess>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExplainParams {
+    /// Defaults to the caller.
+    pub user_id: Option<String>,
+}
+
+/// `GET /api/guilds/{id}/permissions/explain?user_id=` - break down how a
+/// user's permissions in a guild are resolved. Members can explain their own
+/// permissions; explaining someone else's requires `CONFIG_VIEW`.
+#[get("/api/guilds/{id}/permissions/explain")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn explain_permissions(
+    state: web::Data<State>,
+    id: web::Path<String>,
+    params: web::Query<ExplainParams>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<PermissionExplanation>, ApiError> {
+    let guild_id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".into()))?;
+    let target = match &params.user_id {
+        Some(user_id) => {
+            Id::from_str(user_id).map_err(|_| ApiError::ParseError("Invalid user ID".into()))?
+        }
+        None => user.user_id,
+    };
+
+    // Members may explain their own permissions; anyone else needs CONFIG_VIEW.
+    let own_guild = if target == user.user_id && user.api_key.is_none() {
+        state.get_guild(&guild_id).await?
+    } else {
+        None
+    };
+    let own_guild = match own_guild {
+        Some(guild) if state.is_guild_member(&guild, &target).await? => Some(guild),
+        _ => None,
+    };
+
+    let (guild, config) = match own_guild {
+        Some(guild) => {
+            let config = state
+                .get_config(&guild_id)
+                .await?
+                .ok_or_else(|| ApiError::NotFound("Config not found".into()))?;
+            (guild, config)
+        }
+        None => {
+            state
+                .require_guild_permission(&user, &guild_id, Permission::CONFIG_VIEW)
+                .await?
+        }
+    };
+
+    let member_roles = if config.inherit_discord_perms {
+        state.get_member_roles(&guild.id, &target).await?
+    } else {
+        None
+    };
+    let breakdown = resolve_permissions(&config, &guild, Some(&target), member_roles.as_deref());
+    let effective = state
+        .effective_permissions(&config, &guild, &target)
+        .await?;
+
+    Ok(web::Json(PermissionExplanation {
+        user_id: target,
+        owner: guild.owner_id == Some(target),
+        inherit_discord_perms: config.inherit_discord_perms,
+        breakdown,
+        effective,
+        flags: flag_names(effective),
+        staff_access: state.staff.access(&target),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SimulateRequest {
+    /// Candidate replacement for the config's permission groups.
+    pub permission_groups: Vec<Group>,
+    /// Roles of the member to simulate. Replaces a given user's own roles.
+    pub roles: Option<Vec<String>>,
+    /// User to simulate, as listed in groups and with their cached roles.
+    pub user_id: Option<String>,
+}
+
+/// A member's permissions under the current and the candidate permission groups.
+#[derive(Debug, Serialize)]
+pub struct SimulationResult {
+    /// The simulated user is the guild owner, who holds every permission
+    /// under either config.
+    pub owner: bool,
+    /// When off, the member's roles grant nothing and only groups listing
+    /// the user count.
+    pub inherit_discord_perms: bool,
+    pub current: PermissionBreakdown,
+    pub candidate: PermissionBreakdown,
+    pub gained: Vec<String>,
+    pub lost: Vec<String>,
+}
+
+/// `POST /api/guilds/{id}/permissions/simulate` - preview how candidate
+/// permission groups would change a member's permissions, for a user, a set
+/// of roles, or a user with a different set of roles. Requires `CONFIG_VIEW`.
+#[post("/api/guilds/{id}/permissions/simulate")]
+#[instrument(skip(state, user, body), fields(user_id = %user.user_id))]
+pub async fn simulate_permissions(
+    state: web::Data<State>,
+    id: web::Path<String>,
+    body: web::Json<SimulateRequest>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<SimulationResult>, ApiError> {
+    let guild_id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".into()))?;
+    let body = body.into_inner();
+
+    let (guild, config) = state
+        .require_guild_permission(&user, &guild_id, Permission::CONFIG_VIEW)
+        .await?;
+
+    let target = body
+        .user_id
+        .as_deref()
+        .map(Id::from_str)
+        .transpose()
+        .map_err(|_| ApiError::ParseError("Invalid user ID".into()))?;
+    if body.roles.is_some() && !config.inherit_discord_perms {
+        return Err(ApiError::BadRequest(
+            "Roles grant no permissions while inherit_discord_perms is off".into(),
+        ));
+    }
+    let roles = match body.roles {
+        Some(roles) => Some(
+            roles
+                .iter()
+                .map(|role| Id::from_str(role))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|_| ApiError::ParseError("Invalid role ID".into()))?,
+        ),
+        None => match &target {
+            Some(target) => state.get_member_roles(&guild.id, target).await?,
+            None => {
+                return Err(ApiError::BadRequest(
+                    "Either roles or user_id is required".into(),
+                ))
+            }
+        },
+    };
+
+    let mut candidate = config.clone();
+    candidate.permission_groups = Some(body.permission_groups);
+
+    let current = resolve_permissions(&config, &guild, target.as_ref(), roles.as_deref());
+    let candidate = resolve_permissions(&candidate, &guild, target.as_ref(), roles.as_deref());
+
+    Ok(web::Json(SimulationResult {
+        owner: target.is_some() && guild.owner_id == target,
+        inherit_discord_perms: config.inherit_discord_perms,
+        gained: flag_names(candidate.permissions - current.permissions),
+        lost: flag_names(current.permissions - candidate.permissions),
+        current,
+        candidate,
+    }))
+}
+
+/// Flags audited when none are requested.
+const DEFAULT_AUDIT_FLAGS: &[&str] = &["CONFIG_EDIT", "INFRACTION_EDIT", "MODERATION_BAN"];
+
+/// A role holding an audited flag.
+#[derive(Debug, Serialize)]
+pub struct RoleHolder {
+    pub id: Id,
+    /// `None` if the role no longer exists in the guild.
+    pub name: Option<String>,
+    /// Held through the role's Discord permissions.
+    pub discord: bool,
+    /// Permission groups granting it to the role.
+    pub groups: Vec<String>,
+}
+
+/// A user listed directly in a permission group holding an audited flag.
+#[derive(Debug, Serialize)]
+pub struct UserHolder {
+    pub id: Id,
+    pub groups: Vec<String>,
+}
+
+/// Everyone holding one flag.
+#[derive(Debug, Serialize)]
+pub struct FlagHolders {
+    pub flag: String,
+    pub roles: Vec<RoleHolder>,
+    pub users: Vec<UserHolder>,
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PermissionAudit {
+    /// Holds every flag.
+    pub owner: Option<Id>,
+    /// When off, roles grant nothing, through Discord or through groups.
+    pub inherit_discord_perms: bool,
+    pub flags: Vec<FlagHolders>,
+}
+
+/// Invert the permission model for one flag: which roles, directly listed
+/// users and groups hold it under `config`.
+pub fn flag_holders(config: &Config, guild: &Guild, name: &str, flag: Permission) -> FlagHolders {
+    let groups: Vec<&Group> = config
+        .permission_groups
+        .iter()
+        .flatten()
+        .filter(|group| group.permissions.contains(flag))
+        .collect();
+
+    let mut roles: Vec<RoleHolder> = Vec::new();
+    if config.inherit_discord_perms {
+        for role in &guild.roles {
+            if Permission::from_discord_permissions(&guild.roles, &[role.id]).contains(flag) {
+                roles.push(RoleHolder {
+                    id: role.id,
+                    name: Some(role.name.to_string()),
+                    discord: true,
+                    groups: Vec::new(),
+                });
+            }
+        }
+
+        for group in &groups {
+            for role_id in &group.roles {
+                match roles.iter_mut().find(|holder| holder.id == *role_id) {
+                    Some(holder) => holder.groups.push(group.name.clone()),
+                    None => roles.push(RoleHolder {
+                        id: *role_id,
+                        name: guild
+                            .roles
+                            .iter()
+                            .find(|r| r.id == *role_id)
+                            .map(|r| r.name.to_string()),
+                        discord: false,
+                        groups: vec![group.name.clone()],
+                    }),
+                }
+            }
+        }
+    }
+
+    let mut users: Vec<UserHolder> = Vec::new();
+    for group in &groups {
+        for user_id in &group.users {
+            match users.iter_mut().find(|holder| holder.id == *user_id) {
+                Some(holder) => holder.groups.push(group.name.clone()),
+                None => users.push(UserHolder {
+                    id: *user_id,
+                    groups: vec![group.name.clone()],
+                }),
+            }
+        }
+    }
+
+    FlagHolders {
+        flag: name.to_string(),
+        roles,
+        users,
+        groups: groups.iter().map(|group| group.name.clone()).collect(),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditParams {
+    /// Comma-separated flag names. Defaults to `CONFIG_EDIT`, `INFRACTION_EDIT`
+    /// and `MODERATION_BAN`.
+    pub flags: Option<String>,
+}
+
+/// `GET /api/guilds/{id}/permissions/audit?flags=` - every role, directly
+/// listed user and permission group holding each flag. Members holding a flag
+/// through a role are covered by that role's entry. Requires `CONFIG_EDIT`.
+#[get("/api/guilds/{id}/permissions/audit")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn audit_permissions(
+    state: web::Data<State>,
+    id: web::Path<String>,
+    params: web::Query<AuditParams>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<PermissionAudit>, ApiError> {
+    let guild_id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".into()))?;
+
+    let names: Vec<&str> = match &params.flags {
+        Some(flags) => flags
+            .split(',')
+            .map(str::trim)
+            .filter(|flag| !flag.is_empty())
+            .collect(),
+        None => DEFAULT_AUDIT_FLAGS.to_vec(),
+    };
+    let flags = names
+        .iter()
+        .map(|name| {
+            Permission::from_name(name)
+                .map(|flag| (*name, flag))
+                .ok_or_else(|| ApiError::BadRequest(format!("Unknown permission flag: {}", name)))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let (guild, config) = state
+        .require_guild_permission(&user, &guild_id, Permission::CONFIG_EDIT)
+        .await?;
+
+    Ok(web::Json(PermissionAudit {
+        owner: guild.owner_id,
+        inherit_discord_perms: config.inherit_discord_perms,
+        flags: flags
+            .into_iter()
+            .map(|(name, flag)| flag_holders(&config, &guild, name, flag))
+            .collect(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::api_keys::ApiKeyScope;
+
+    fn id(n: u64) -> Id {
+        Id::from_str(&n.to_string()).unwrap()
+    }
+
+    const OWNER: u64 = 2;
+    const MODS: u64 = 10;
+    const HELPERS: u64 = 11;
+
+    fn guild() -> Guild {
+        serde_json::from_value(json!({
+            "id": id(1),
+            "name": "guild",
+            "icon": null,
+            "owner_id": id(OWNER),
+            "roles": [
+                { "id": id(MODS), "name": "mods", "position": 2, "permissions": 0, "managed": false },
+                { "id": id(HELPERS), "name": "helpers", "position": 1, "permissions": 0, "managed": false },
+            ],
+            "member_count": null,
+            "approximate_member_count": null,
+        }))
+        .unwrap()
+    }
+
+    /// `moderators` grants kick and ban to the mods role and user 3;
+    /// `viewers` grants config view to the helpers role.
+    fn config(inherit_discord_perms: bool) -> Config {
+        serde_json::from_value(json!({
+            "id": id(1),
+            "prefix": "!",
+            "mute_role": null,
+            "default_warn_duration": null,
+            "log_channel": null,
+            "prefer_embeds": false,
+            "inherit_discord_perms": inherit_discord_perms,
+            "alert_on_infraction": false,
+            "send_permission_denied": false,
+            "moderation_enabled": true,
+            "music_enabled": false,
+            "automod_enabled": false,
+            "permission_groups": [
+                {
+                    "name": "moderators",
+                    "roles": [id(MODS)],
+                    "users": [id(3)],
+                    "permissions": Permission::MODERATION_KICK | Permission::MODERATION_BAN,
+                },
+                {
+                    "name": "viewers",
+                    "roles": [id(HELPERS)],
+                    "users": [],
+                    "permissions": Permission::CONFIG_VIEW,
+                },
+            ],
+            "automod": null,
+            "command_aliases": null,
+        }))
+        .unwrap()
+    }
+
+    fn group_names(breakdown: &PermissionBreakdown) -> Vec<&str> {
+        breakdown.groups.iter().map(|g| g.name.as_str()).collect()
+    }
+
+    #[test]
+    fn matches_groups_by_user() {
+        let breakdown = resolve_permissions(&config(true), &guild(), Some(&id(3)), None);
+
+        assert_eq!(group_names(&breakdown), ["moderators"]);
+        assert!(breakdown.groups[0].by_user);
+        assert!(breakdown.groups[0].by_roles.is_empty());
+        assert_eq!(
+            breakdown.permissions,
+            Permission::MODERATION_KICK | Permission::MODERATION_BAN
+        );
+    }
+
+    #[test]
+    fn matches_groups_by_role_when_inheriting() {
+        let roles = [id(MODS), id(HELPERS)];
+        let breakdown = resolve_permissions(&config(true), &guild(), Some(&id(4)), Some(&roles));
+
+        assert_eq!(group_names(&breakdown), ["moderators", "viewers"]);
+        assert!(!breakdown.groups[0].by_user);
+        assert_eq!(breakdown.groups[0].by_roles, [id(MODS)]);
+        assert_eq!(breakdown.member_roles.as_deref(), Some(&roles[..]));
+        assert_eq!(
+            breakdown.permissions,
+            Permission::MODERATION_KICK | Permission::MODERATION_BAN | Permission::CONFIG_VIEW
+        );
+    }
+
+    #[test]
+    fn ignores_roles_without_inheritance() {
+        let roles = [id(MODS)];
+        let breakdown = resolve_permissions(&config(false), &guild(), Some(&id(3)), Some(&roles));
+
+        assert_eq!(group_names(&breakdown), ["moderators"]);
+        assert!(breakdown.groups[0].by_roles.is_empty());
+        assert!(breakdown.member_roles.is_none());
+        assert_eq!(breakdown.discord, Permission::empty());
+    }
+
+    #[test]
+    fn resolves_hypothetical_members_by_roles() {
+        let roles = [id(HELPERS)];
+        let breakdown = resolve_permissions(&config(true), &guild(), None, Some(&roles));
+
+        assert_eq!(group_names(&breakdown), ["viewers"]);
+        assert_eq!(breakdown.permissions, Permission::CONFIG_VIEW);
+    }
+
+    #[test]
+    fn owners_get_no_bypass_from_resolution() {
+        let breakdown = resolve_permissions(&config(true), &guild(), Some(&id(OWNER)), None);
+
+        assert!(breakdown.groups.is_empty());
+        assert_eq!(breakdown.permissions, Permission::empty());
+    }
+
+    fn holders(config: &Config, flag: Permission) -> FlagHolders {
+        flag_holders(config, &guild(), "FLAG", flag)
+    }
+
+    #[test]
+    fn lists_group_roles_and_users_holding_a_flag() {
+        let mut config = config(true);
+        config.permission_groups.as_mut().unwrap().push(Group {
+            name: "admins".into(),
+            roles: vec![id(MODS), id(99)],
+            users: vec![id(3), id(5)],
+            permissions: Permission::MODERATION_BAN | Permission::CONFIG_EDIT,
+        });
+
+        let ban = holders(&config, Permission::MODERATION_BAN);
+        assert_eq!(ban.flag, "FLAG");
+        assert_eq!(ban.groups, ["moderators", "admins"]);
+
+        let roles: Vec<_> = ban
+            .roles
+            .iter()
+            .map(|r| (r.id, r.name.as_deref(), r.discord, r.groups.clone()))
+            .collect();
+        assert_eq!(
+            roles,
+            [
+                (
+                    id(MODS),
+                    Some("mods"),
+                    false,
+                    vec!["moderators".to_string(), "admins".to_string()]
+                ),
+                (id(99), None, false, vec!["admins".to_string()]),
+            ]
+        );
+
+        let users: Vec<_> = ban.users.iter().map(|u| (u.id, u.groups.clone())).collect();
+        assert_eq!(
+            users,
+            [
+                (id(3), vec!["moderators".to_string(), "admins".to_string()]),
+                (id(5), vec!["admins".to_string()]),
+            ]
+        );
+    }
+
+    #[test]
+    fn roles_hold_nothing_without_inheritance() {
+        let view = holders(&config(false), Permission::CONFIG_VIEW);
+        assert_eq!(view.groups, ["viewers"]);
+        assert!(view.roles.is_empty());
+        assert!(view.users.is_empty());
+
+        let kick = holders(&config(false), Permission::MODERATION_KICK);
+        assert!(kick.roles.is_empty());
+        assert_eq!(kick.users.len(), 1);
+    }
+
+    #[test]
+    fn unheld_flags_have_no_holders() {
+        let edit = holders(&config(true), Permission::INFRACTION_EDIT);
+
+        assert!(edit.groups.is_empty());
+        assert!(edit.roles.is_empty());
+        assert!(edit.users.is_empty());
+    }
+
+    fn user(user_id: u64, api_key: bool) -> AuthenticatedUser {
+        AuthenticatedUser {
+            user_id: id(user_id),
+            session_id: None,
+            api_key: api_key.then(|| ApiKeyScope {
+                key_id: "key".into(),
+                guild_id: id(1),
+                permissions: Permission::all(),
+            }),
+            staff_access: Default::default(),
+        }
+    }
+
+    fn gains(who: &str, perms: Permission) -> String {
+        format!("{} would gain {:?}", who, perms)
+    }
+
+    fn groups(config: &mut Config) -> &mut Vec<Group> {
+        config.permission_groups.as_mut().unwrap()
+    }
+
+    #[test]
+    fn adding_yourself_to_a_group_is_an_escalation() {
+        let before = config(true);
+        let mut after = before.clone();
+        groups(&mut after)[0].users.push(id(5));
+
+        assert_eq!(
+            escalations(&guild(), &before, &after, Permission::MODERATION_KICK),
+            [gains("user 5", Permission::MODERATION_BAN)]
+        );
+        assert!(escalations(&guild(), &before, &after, Permission::all()).is_empty());
+    }
+
+    #[test]
+    fn adding_group_permissions_escalates_every_member() {
+        let before = config(true);
+        let mut after = before.clone();
+        groups(&mut after)[0].permissions |= Permission::CONFIG_EDIT;
+
+        assert_eq!(
+            escalations(&guild(), &before, &after, Permission::CONFIG_VIEW),
+            [
+                gains("role mods (10)", Permission::CONFIG_EDIT),
+                gains("user 3", Permission::CONFIG_EDIT),
+            ]
+        );
+    }
+
+    #[test]
+    fn removing_permissions_is_never_an_escalation() {
+        let before = config(true);
+        let mut after = before.clone();
+        groups(&mut after).remove(0);
+
+        assert!(escalations(&guild(), &before, &after, Permission::empty()).is_empty());
+    }
+
+    #[test]
+    fn enabling_inheritance_grants_group_roles() {
+        let before = config(false);
+        let after = config(true);
+
+        assert_eq!(
+            escalations(&guild(), &before, &after, Permission::MODERATION_KICK),
+            [
+                gains("role helpers (11)", Permission::CONFIG_VIEW),
+                gains("role mods (10)", Permission::MODERATION_BAN),
+            ]
+        );
+        assert!(escalations(&guild(), &after, &before, Permission::empty()).is_empty());
+    }
+
+    #[test]
+    fn group_roles_are_dormant_without_inheritance() {
+        let before = config(false);
+        let mut after = before.clone();
+        groups(&mut after)[1].roles.push(id(MODS));
+
+        assert!(escalations(&guild(), &before, &after, Permission::empty()).is_empty());
+    }
+
+    #[test]
+    fn only_the_owner_is_exempt_from_escalation_checks() {
+        assert!(escalation_exempt(&guild(), &user(OWNER, false)));
+        assert!(!escalation_exempt(&guild(), &user(OWNER, true)));
+        assert!(!escalation_exempt(&guild(), &user(3, false)));
+    }
+
+    #[test]
+    fn names_set_flags() {
+        assert_eq!(
+            flag_names(Permission::MODERATION_BAN | Permission::CONFIG_VIEW),
+            ["MODERATION_BAN", "CONFIG_VIEW"]
+        );
+        assert!(flag_names(Permission::empty()).is_empty());
+    }
+}
@@ -0,0 +1,506 @@
+use std::time::Duration;
+
+use bm_lib::discord::Id;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::{
+    auth::AuthenticatedUser, crypto, discord::DiscordOAuthResponse, error::ApiError, store::Swap,
+    State,
+};
+
+/// Discord OAuth credentials for a session. Only ever stored sealed with
+/// [`crate::crypto::SecretBox`] inside the owning [`Session`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DiscordCredentials {
+    pub access_token: String,
+    pub refresh_token: String,
+    pub token_type: String,
+    pub scope: String,
+    /// Unix timestamp (seconds) when the access token expires.
+    pub expires_at: i64,
+}
+
+impl From<DiscordOAuthResponse> for DiscordCredentials {
+    fn from(response: DiscordOAuthResponse) -> Self {
+        Self {
+            access_token: response.access_token,
+            refresh_token: response.refresh_token,
+            token_type: response.token_type,
+            scope: response.scope,
+            expires_at: Utc::now().timestamp() + response.expires_in as i64,
+        }
+    }
+}
+
+/// Server-side record backing a dashboard JWT. The token's `jti` claim is the
+/// session ID, so revoking the record invalidates every token carrying it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Session {
+    pub id: String,
+    pub user_id: Id,
+    /// Unix timestamp (seconds) when the session was created.
+    pub created_at: i64,
+    /// Unix timestamp (seconds) after which the session is no longer valid.
+    pub expires_at: i64,
+    /// Sealed [`DiscordCredentials`], bound to this session's ID.
+    pub discord: String,
+}
+
+/// Whether a session can still be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionStatus {
+    Active,
+    Revoked,
+    Expired,
+}
+
+/// Opaque refresh token handed to clients: `{session_id}.{secret}`.
+fn format_refresh_token(session_id: &str, secret: &str) -> String {
+    format!("{}.{}", session_id, secret)
+}
+
+/// Split a refresh token into its session ID and secret.
+pub fn parse_refresh_token(token: &str) -> Option<(&str, &str)> {
+    token
+        .split_once('.')
+        .filter(|(id, secret)| !id.is_empty() && !secret.is_empty())
+}
+
+/// An OAuth login that has been started but not yet completed.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PendingLogin {
+    pub redirect_uri: String,
+    pub code_verifier: String,
+}
+
+/// How long a user has to complete the Discord consent screen.
+const PENDING_LOGIN_TTL: Duration = Duration::from_secs(600);
+
+#[inline]
+fn pending_login_cache_key(state: &str) -> String {
+    format!("oauth_state:{}", state)
+}
+
+#[inline]
+fn session_cache_key(session_id: &str) -> String {
+    format!("session:{}", session_id)
+}
+
+/// Hash of the only refresh token currently accepted for a session, kept in
+/// the [`crate::store::Store`] so it can be swapped atomically.
+#[inline]
+fn refresh_hash_key(session_id: &str) -> String {
+    format!("session_refresh:{}", session_id)
+}
+
+/// Marks a revoked session. Kept apart from the session record, so saving a
+/// copy of the session loaded before it was revoked can't undo the revocation.
+#[inline]
+fn session_revoked_cache_key(session_id: &str) -> String {
+    format!("session_revoked:{}", session_id)
+}
+
+#[inline]
+fn revoked_before_cache_key(user_id: &Id) -> String {
+    format!("sessions_revoked_before:{}", user_id)
+}
+
+/// Refresh the Discord access token when it has less than this many seconds left.
+const DISCORD_REFRESH_THRESHOLD: i64 = 24 * 60 * 60;
+
+/// How long a Discord token refresh may hold its session's lock.
+const DISCORD_REFRESH_LOCK_TTL: Duration = Duration::from_secs(15);
+
+/// How long a request waits for another request's refresh of the same session.
+const DISCORD_REFRESH_LOCK_WAIT: Duration = Duration::from_secs(10);
+
+#[inline]
+fn discord_refresh_lock_key(session_id: &str) -> String {
+    format!("discord_refresh_lock:{}", session_id)
+}
+
+/// Whether the Discord access token is close enough to expiry to refresh.
+fn needs_discord_refresh(credentials: &DiscordCredentials) -> bool {
+    credentials.expires_at - Utc::now().timestamp() <= DISCORD_REFRESH_THRESHOLD
+}
+
+/// Remaining lifetime of a session, used as its cache TTL.
+fn remaining_ttl(expires_at: i64) -> Duration {
+    Duration::from_secs((expires_at - Utc::now().timestamp()).max(1) as u64)
+}
+
+impl State {
+    /// Start an OAuth login: generate a `state` value and PKCE verifier and
+    /// remember them until the callback. Returns the state.
+    #[instrument(skip(self))]
+    pub async fn begin_login(
+        &self,
+        redirect_uri: &str,
+    ) -> Result<(String, PendingLogin), ApiError> {
+        let state = crypto::random_token(32);
+        let pending = PendingLogin {
+            redirect_uri: redirect_uri.to_string(),
+            code_verifier: crypto::random_token(32),
+        };
+
+        let value = serde_json::to_string(&pending)
+            .map_err(|e| ApiError::Internal(format!("Failed to serialize login: {e}")))?;
+        self.store
+            .set(&pending_login_cache_key(&state), &value, PENDING_LOGIN_TTL)
+            .await?;
+
+        Ok((state, pending))
+    }
+
+    /// Consume the pending login for `state`. Each state is single-use and is
+    /// taken atomically, so a replayed, concurrent or forged callback finds
+    /// nothing.
+    #[instrument(skip(self, state))]
+    pub async fn complete_login(&self, state: &str) -> Result<PendingLogin, ApiError> {
+        let value = self
+            .store
+            .take(&pending_login_cache_key(state))
+            .await?
+            .ok_or_else(|| ApiError::Auth("Invalid or expired OAuth state".into()))?;
+
+        serde_json::from_str(&value)
+            .map_err(|e| ApiError::Internal(format!("Invalid pending login: {e}")))
+    }
+
+    /// Create and persist a new session for `user_id`, returning it together
+    /// with its first refresh token.
+    #[instrument(skip(self, credentials))]
+    pub async fn create_session(
+        &self,
+        user_id: &Id,
+        credentials: &DiscordCredentials,
+    ) -> Result<(Session, String), ApiError> {
+        let id = Uuid::new_v4().to_string();
+        let session = Session {
+            discord: self.secrets.seal(credentials, &id)?,
+            id,
+            user_id: *user_id,
+            created_at: Utc::now().timestamp(),
+            expires_at: self.refresh_expiry(),
+        };
+
+        let secret = crypto::random_token(32);
+        self.store
+            .set(
+                &refresh_hash_key(&session.id),
+                &crypto::hash_token(&secret),
+                self.refresh_token_ttl,
+            )
+            .await?;
+        self.save_session(&session).await?;
+
+        let token = format_refresh_token(&session.id, &secret);
+        Ok((session, token))
+    }
+
+    /// Redeem a refresh token: swap it for a new one and extend the session
+    /// by the refresh token lifetime. The check and swap are a single atomic
+    /// step, so each token can be redeemed once. A well-formed token for a
+    /// live session that isn't the current one means it was already redeemed,
+    /// i.e. it leaked, is being replayed or lost a race with a concurrent
+    /// redemption, so the whole session is revoked.
+    #[instrument(skip(self, refresh_token))]
+    pub async fn redeem_refresh_token(
+        &self,
+        refresh_token: &str,
+    ) -> Result<(Session, String), ApiError> {
+        let (session_id, secret) = parse_refresh_token(refresh_token)
+            .ok_or_else(|| ApiError::Auth("Invalid refresh token".into()))?;
+
+        let session = self.load_active_session(session_id).await?;
+
+        let next = crypto::random_token(32);
+        let swap = self
+            .store
+            .compare_and_set(
+                &refresh_hash_key(&session.id),
+                &crypto::hash_token(secret),
+                &crypto::hash_token(&next),
+                self.refresh_token_ttl,
+            )
+            .await?;
+
+        match swap {
+            Swap::Swapped => {}
+            Swap::Mismatch => {
+                tracing::warn!(session_id = %session.id, user_id = %session.user_id, "Refresh token reuse detected, revoking session");
+                self.revoke_session(&session).await?;
+                return Err(ApiError::Auth(
+                    "Refresh token reuse detected; session revoked".into(),
+                ));
+            }
+            Swap::Missing => return Err(ApiError::Auth("Session expired".into())),
+        }
+
+        let session = Session {
+            expires_at: self.refresh_expiry(),
+            ..session
+        };
+        self.save_session(&session).await?;
+
+        let token = format_refresh_token(&session.id, &next);
+        Ok((session, token))
+    }
+
+    /// Expiry for a session whose refresh token was just issued.
+    fn refresh_expiry(&self) -> i64 {
+        Utc::now().timestamp() + self.refresh_token_ttl.as_secs() as i64
+    }
+
+    #[instrument(skip(self))]
+    pub async fn get_session(&self, session_id: &str) -> Result<Option<Session>, ApiError> {
+        let key = session_cache_key(session_id);
+        self.cache
+            .get::<String, Session>(&key)
+            .await
+            .map_err(ApiError::from)
+    }
+
+    #[instrument(skip(self, session), fields(session_id = %session.id))]
+    pub async fn save_session(&self, session: &Session) -> Result<(), ApiError> {
+        let key = session_cache_key(&session.id);
+        self.cache
+            .set(&key, session, Some(remaining_ttl(session.expires_at)))
+            .await
+            .map_err(ApiError::from)
+    }
+
+    /// Mark a single session as revoked. The marker lives as long as a refresh
+    /// token, which outlasts the session record, so tokens carrying its ID
+    /// keep being rejected.
+    #[instrument(skip(self, session), fields(session_id = %session.id))]
+    pub async fn revoke_session(&self, session: &Session) -> Result<(), ApiError> {
+        let key = session_revoked_cache_key(&session.id);
+        self.cache
+            .set(&key, &true, Some(self.refresh_token_ttl))
+            .await
+            .map_err(ApiError::from)
+    }
+
+    /// Revoke every session the user currently holds ("log out all devices").
+    /// Sessions created up to this second are rejected by [`State::check_session`].
+    /// The marker lives as long as a refresh token, after which every session
+    /// it covers has expired anyway.
+    #[instrument(skip(self))]
+    pub async fn revoke_all_sessions(&self, user_id: &Id) -> Result<(), ApiError> {
+        let key = revoked_before_cache_key(user_id);
+        self.cache
+            .set(&key, &Utc::now().timestamp(), Some(self.refresh_token_ttl))
+            .await
+            .map_err(ApiError::from)
+    }
+
+    /// Load a session and verify it is still usable by `user_id`.
+    #[instrument(skip(self))]
+    pub async fn check_session(&self, session_id: &str, user_id: &Id) -> Result<Session, ApiError> {
+        let session = self.load_active_session(session_id).await?;
+
+        if session.user_id != *user_id {
+            return Err(ApiError::Auth("Invalid session".into()));
+        }
+
+        Ok(session)
+    }
+
+    /// Current status of a session, taking "log out all devices" into account.
+    #[instrument(skip(self, session), fields(session_id = %session.id))]
+    pub async fn session_status(&self, session: &Session) -> Result<SessionStatus, ApiError> {
+        let key = session_revoked_cache_key(&session.id);
+        if self.cache.get::<String, bool>(&key).await?.is_some() {
+            return Ok(SessionStatus::Revoked);
+        }
+        if session.expires_at <= Utc::now().timestamp() {
+            return Ok(SessionStatus::Expired);
+        }
+
+        let key = revoked_before_cache_key(&session.user_id);
+        if let Some(revoked_before) = self.cache.get::<String, i64>(&key).await? {
+            if session.created_at <= revoked_before {
+                return Ok(SessionStatus::Revoked);
+            }
+        }
+
+        Ok(SessionStatus::Active)
+    }
+
+    /// Load a session, rejecting it if it expired or was revoked.
+    #[instrument(skip(self))]
+    async fn load_active_session(&self, session_id: &str) -> Result<Session, ApiError> {
+        let session = self
+            .get_session(session_id)
+            .await?
+            .ok_or_else(|| ApiError::Auth("Session expired".into()))?;
+
+        if self.session_status(&session).await? != SessionStatus::Active {
+            return Err(ApiError::Auth("Session revoked".into()));
+        }
+
+        Ok(session)
+    }
+
+    /// Decrypt the Discord credentials for the caller's session. Only handlers
+    /// that actually talk to Discord on the user's behalf should need this.
+    #[instrument(skip(self, user), fields(user_id = %user.user_id))]
+    pub async fn discord_credentials(
+        &self,
+        user: &AuthenticatedUser,
+    ) -> Result<DiscordCredentials, ApiError> {
+        let session = self
+            .check_session(user.session_id()?, &user.user_id)
+            .await?;
+        self.secrets.open(&session.discord, &session.id)
+    }
+
+    /// Exchange the session's Discord refresh token for a new token pair if the
+    /// access token is close to expiry, rotating both tokens in the session.
+    /// Returns the (possibly updated) session.
+    ///
+    /// Discord rotates refresh tokens, so concurrent refreshes of one session
+    /// would spend the same token twice and the loser would get `invalid_grant`.
+    /// Refreshes are therefore serialized per session.
+    #[instrument(skip(self, session), fields(session_id = %session.id))]
+    pub async fn refresh_discord_credentials(&self, session: Session) -> Result<Session, ApiError> {
+        let credentials: DiscordCredentials = self.secrets.open(&session.discord, &session.id)?;
+        if !needs_discord_refresh(&credentials) {
+            return Ok(session);
+        }
+
+        let lock = self
+            .store
+            .lock(
+                &discord_refresh_lock_key(&session.id),
+                DISCORD_REFRESH_LOCK_TTL,
+                DISCORD_REFRESH_LOCK_WAIT,
+            )
+            .await?
+            .ok_or_else(|| {
+                ApiError::Conflict(
+                    "Discord authorization is already being refreshed, please retry".into(),
+                )
+            })?;
+
+        let result = self.refresh_discord_credentials_locked(&session.id).await;
+
+        if let Err(e) = self.store.unlock(lock).await {
+            tracing::warn!(error = %e, "Failed to release Discord refresh lock");
+        }
+
+        result
+    }
+
+    /// [`State::refresh_discord_credentials`] while holding the session's
+    /// refresh lock.
+    async fn refresh_discord_credentials_locked(
+        &self,
+        session_id: &str,
+    ) -> Result<Session, ApiError> {
+        // Another request may have refreshed while we waited for the lock.
+        let session = self.load_active_session(session_id).await?;
+        let credentials: DiscordCredentials = self.secrets.open(&session.discord, &session.id)?;
+        if !needs_discord_refresh(&credentials) {
+            return Ok(session);
+        }
+
+        let refreshed = match self.rest.refresh_token(&credentials.refresh_token).await {
+            Ok(response) => DiscordCredentials::from(response),
+            Err(e) if e.status().is_some_and(|s| s.is_client_error()) => {
+                tracing::info!(error = %e, "Discord rejected refresh token");
+
+                // Only a rejected current token means the grant is dead; if the
+                // stored one changed meanwhile, ours was merely stale.
+                let current = self.load_active_session(session_id).await?;
+                let stored: DiscordCredentials =
+                    self.secrets.open(&current.discord, &current.id)?;
+                if stored.refresh_token != credentials.refresh_token {
+                    return Ok(current);
+                }
+
+                // The Discord grant is dead, so this session can never talk to Discord again.
+                self.revoke_session(&current).await?;
+                return Err(ApiError::ReauthRequired(
+                    "Discord authorization expired or was revoked, please log in again".into(),
+                ));
+            }
+            Err(e) => return Err(ApiError::Discord(e)),
+        };
+
+        let session = Session {
+            discord: self.secrets.seal(&refreshed, &session.id)?,
+            ..session
+        };
+        self.save_session(&session).await?;
+
+        Ok(session)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refresh_tokens_round_trip() {
+        let token = format_refresh_token("session", "secret");
+
+        assert_eq!(token, "session.secret");
+        assert_eq!(parse_refresh_token(&token), Some(("session", "secret")));
+    }
+
+    #[test]
+    fn rejects_malformed_refresh_tokens() {
+        assert_eq!(parse_refresh_token(""), None);
+        assert_eq!(parse_refresh_token("session"), None);
+        assert_eq!(parse_refresh_token(".secret"), None);
+        assert_eq!(parse_refresh_token("session."), None);
+    }
+
+    #[test]
+    fn refresh_secrets_are_stored_hashed() {
+        let secret = crypto::random_token(32);
+        let hash = crypto::hash_token(&secret);
+
+        assert_ne!(hash, secret);
+        assert_eq!(hash, crypto::hash_token(&secret));
+        assert_ne!(hash, crypto::hash_token(&crypto::random_token(32)));
+    }
+
+    fn credentials(expires_in: i64) -> DiscordCredentials {
+        DiscordCredentials {
+            access_token: "access".into(),
+            refresh_token: "refresh".into(),
+            token_type: "Bearer".into(),
+            scope: "identify guilds".into(),
+            expires_at: Utc::now().timestamp() + expires_in,
+        }
+    }
+
+    #[test]
+    fn discord_tokens_refresh_within_a_day_of_expiry() {
+        assert!(!needs_discord_refresh(&credentials(
+            DISCORD_REFRESH_THRESHOLD + 60
+        )));
+        assert!(needs_discord_refresh(&credentials(
+            DISCORD_REFRESH_THRESHOLD - 60
+        )));
+        assert!(needs_discord_refresh(&credentials(-60)));
+    }
+
+    #[test]
+    fn sessions_are_cached_until_they_expire() {
+        let now = Utc::now().timestamp();
+
+        let ttl = remaining_ttl(now + 3600).as_secs();
+        assert!((3599..=3600).contains(&ttl));
+        // Expired sessions still get a positive TTL, which Redis requires.
+        assert_eq!(remaining_ttl(now - 10), Duration::from_secs(1));
+    }
+}
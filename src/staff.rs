@@ -0,0 +1,306 @@
+use std::{cell::Cell, rc::Rc};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    get,
+    http::header::{HeaderName, HeaderValue},
+    middleware::Next,
+    web, HttpMessage,
+};
+use bm_lib::{discord::Id, permissions::Permission};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{auth::AuthenticatedUser, config::Settings, error::ApiError, State};
+
+/// Response header set when a request was only allowed through staff access.
+pub const STAFF_ACCESS_HEADER: &str = "x-staff-access";
+
+/// Staff access records returned per page by default.
+const STAFF_AUDIT_PAGE_SIZE: usize = 100;
+
+/// Most staff access records returned per page.
+const STAFF_AUDIT_MAX_PAGE_SIZE: usize = 500;
+
+/// Level of cross-guild access a bot staff member has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StaffAccess {
+    /// View config and infractions in any guild.
+    ReadOnly,
+    /// Everything a guild owner can do, in any guild.
+    Full,
+}
+
+impl StaffAccess {
+    pub fn allows(self, perm: Permission) -> bool {
+        match self {
+            StaffAccess::Full => true,
+            StaffAccess::ReadOnly => {
+                (Permission::CONFIG_VIEW | Permission::INFRACTION_VIEW).contains(perm)
+            }
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            StaffAccess::ReadOnly => "read_only",
+            StaffAccess::Full => "full",
+        }
+    }
+}
+
+/// Bot staff members, configured with `STAFF_USER_IDS` (full access) and
+/// `STAFF_READ_ONLY_USER_IDS`.
+#[derive(Debug, Default)]
+pub struct StaffList {
+    members: Vec<(Id, StaffAccess)>,
+}
+
+impl StaffList {
+    pub fn from_settings(settings: &Settings) -> std::io::Result<Self> {
+        Self::from_ids(&settings.staff_user_ids, &settings.staff_read_only_user_ids)
+    }
+
+    fn from_ids(full: &[String], read_only: &[String]) -> std::io::Result<Self> {
+        let mut members = Vec::new();
+        for (ids, access) in [
+            (read_only, StaffAccess::ReadOnly),
+            (full, StaffAccess::Full),
+        ] {
+            for id in ids {
+                let id = Id::from_str(id).map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("Invalid staff user ID: {id}"),
+                    )
+                })?;
+                // Full access wins if an ID is listed twice.
+                members.retain(|(member, _)| *member != id);
+                members.push((id, access));
+            }
+        }
+
+        Ok(Self { members })
+    }
+
+    pub fn access(&self, user_id: &Id) -> Option<StaffAccess> {
+        self.members
+            .iter()
+            .find(|(id, _)| id == user_id)
+            .map(|(_, access)| *access)
+    }
+}
+
+/// Per-request flag set when a permission check passed only because of staff
+/// access. Shared between [`AuthenticatedUser`] and the request extensions so
+/// [`staff_access_header`] can report it on the response.
+#[derive(Debug, Clone, Default)]
+pub struct StaffAccessMarker(Rc<Cell<Option<StaffAccess>>>);
+
+impl StaffAccessMarker {
+    pub fn mark(&self, access: StaffAccess) {
+        self.0.set(Some(access));
+    }
+
+    pub fn get(&self) -> Option<StaffAccess> {
+        self.0.get()
+    }
+}
+
+/// A recorded use of staff access in a guild.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StaffAccessRecord {
+    pub user_id: Id,
+    pub guild_id: Id,
+    pub access: StaffAccess,
+    pub permission: Permission,
+    /// Unix timestamp (seconds).
+    pub timestamp: i64,
+}
+
+/// Append-only list of a guild's staff access records, newest first. Records
+/// are never trimmed.
+#[inline]
+fn staff_audit_list_key(guild_id: &Id) -> String {
+    format!("staff_audit:{}", guild_id)
+}
+
+impl State {
+    /// A page of a guild's staff access records, newest first.
+    #[instrument(skip(self))]
+    pub async fn get_staff_access_log(
+        &self,
+        guild_id: &Id,
+        offset: usize,
+        limit: usize,
+    ) -> Result<Vec<StaffAccessRecord>, ApiError> {
+        self.store
+            .list_range(&staff_audit_list_key(guild_id), offset, limit)
+            .await?
+            .iter()
+            .map(|record| {
+                serde_json::from_str(record)
+                    .map_err(|e| ApiError::Internal(format!("Invalid staff access record: {e}")))
+            })
+            .collect()
+    }
+
+    /// Log and persist a use of staff access.
+    #[instrument(skip(self))]
+    pub async fn record_staff_access(
+        &self,
+        user_id: &Id,
+        guild_id: &Id,
+        access: StaffAccess,
+        permission: Permission,
+    ) -> Result<(), ApiError> {
+        tracing::warn!(
+            user_id = %user_id,
+            guild_id = %guild_id,
+            access = access.as_str(),
+            permission = permission.bits(),
+            "Staff access used"
+        );
+
+        let record = serde_json::to_string(&StaffAccessRecord {
+            user_id: *user_id,
+            guild_id: *guild_id,
+            access,
+            permission,
+            timestamp: Utc::now().timestamp(),
+        })
+        .map_err(|e| ApiError::Internal(format!("Failed to serialize staff access: {e}")))?;
+
+        self.store
+            .list_push_front(&staff_audit_list_key(guild_id), &record)
+            .await
+            .map_err(ApiError::from)
+    }
+}
+
+/// Middleware adding [`STAFF_ACCESS_HEADER`] to responses served through staff access.
+pub async fn staff_access_header(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let marker = StaffAccessMarker::default();
+    req.extensions_mut().insert(marker.clone());
+
+    let mut res = next.call(req).await?;
+    if let Some(access) = marker.get() {
+        res.headers_mut().insert(
+            HeaderName::from_static(STAFF_ACCESS_HEADER),
+            HeaderValue::from_static(access.as_str()),
+        );
+    }
+
+    Ok(res)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct StaffAccessQuery {
+    pub offset: Option<usize>,
+    /// Defaults to 100, at most 500.
+    pub limit: Option<usize>,
+}
+
+/// `GET /api/guilds/{id}/staff-access` - when bot staff used their access in
+/// this guild, newest first.
+#[get("/api/guilds/{id}/staff-access")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn list_staff_access(
+    state: web::Data<State>,
+    path: web::Path<String>,
+    query: web::Query<StaffAccessQuery>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Vec<StaffAccessRecord>>, ApiError> {
+    let guild_id = Id::from_str(&path.into_inner())
+        .map_err(|_| ApiError::ParseError("Invalid guild ID".into()))?;
+
+    state
+        .require_guild_permission(&user, &guild_id, Permission::CONFIG_EDIT)
+        .await?;
+
+    let limit = query
+        .limit
+        .unwrap_or(STAFF_AUDIT_PAGE_SIZE)
+        .min(STAFF_AUDIT_MAX_PAGE_SIZE);
+    let log = state
+        .get_staff_access_log(&guild_id, query.offset.unwrap_or(0), limit)
+        .await?;
+
+    Ok(web::Json(log))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn read_only_access_only_allows_viewing() {
+        assert!(StaffAccess::ReadOnly.allows(Permission::CONFIG_VIEW));
+        assert!(StaffAccess::ReadOnly.allows(Permission::INFRACTION_VIEW));
+        assert!(!StaffAccess::ReadOnly.allows(Permission::CONFIG_EDIT));
+        assert!(!StaffAccess::ReadOnly.allows(Permission::MODERATION_BAN));
+        assert!(!StaffAccess::ReadOnly.allows(Permission::CONFIG_VIEW | Permission::CONFIG_EDIT));
+    }
+
+    #[test]
+    fn full_access_allows_everything() {
+        assert!(StaffAccess::Full.allows(Permission::all()));
+    }
+
+    #[test]
+    fn staff_list_resolves_access_levels() {
+        let staff = StaffList::from_ids(&ids(&["1"]), &ids(&["2"])).unwrap();
+
+        assert_eq!(
+            staff.access(&Id::from_str("1").unwrap()),
+            Some(StaffAccess::Full)
+        );
+        assert_eq!(
+            staff.access(&Id::from_str("2").unwrap()),
+            Some(StaffAccess::ReadOnly)
+        );
+        assert_eq!(staff.access(&Id::from_str("3").unwrap()), None);
+    }
+
+    #[test]
+    fn full_access_wins_when_listed_twice() {
+        let staff = StaffList::from_ids(&ids(&["1"]), &ids(&["1"])).unwrap();
+
+        assert_eq!(
+            staff.access(&Id::from_str("1").unwrap()),
+            Some(StaffAccess::Full)
+        );
+    }
+
+    #[test]
+    fn staff_list_rejects_invalid_ids() {
+        assert!(StaffList::from_ids(&ids(&["not-an-id"]), &[]).is_err());
+    }
+
+    #[test]
+    fn staff_access_records_round_trip() {
+        let record = StaffAccessRecord {
+            user_id: Id::from_str("1").unwrap(),
+            guild_id: Id::from_str("2").unwrap(),
+            access: StaffAccess::ReadOnly,
+            permission: Permission::CONFIG_VIEW,
+            timestamp: 1_700_000_000,
+        };
+        let value = serde_json::to_value(&record).unwrap();
+        assert_eq!(value["access"], "read_only");
+
+        let parsed: StaffAccessRecord = serde_json::from_value(value).unwrap();
+        assert_eq!(parsed.access, StaffAccess::ReadOnly);
+        assert_eq!(parsed.permission, Permission::CONFIG_VIEW);
+    }
+}
@@ -0,0 +1,373 @@
+use std::time::Duration;
+
+use redis::{aio::ConnectionManager, AsyncCommands, Client, RedisResult, Script};
+use tracing::instrument;
+
+use crate::crypto;
+
+/// How often [`Store::lock`] retries while another holder has the lock.
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Replaces a value only if it still equals the expected one. Returns 1 when
+/// swapped, 0 when the value differs and -1 when the key doesn't exist.
+const COMPARE_AND_SET_SCRIPT: &str = r#"
+local current = redis.call('GET', KEYS[1])
+if not current then
+    return -1
+end
+if current ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2], 'PX', ARGV[3])
+return 1
+"#;
+
+/// Deletes a lock only if it still holds the caller's token, so a holder whose
+/// lock expired can't release someone else's.
+const UNLOCK_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+"#;
+
+/// Direct Redis access for state that must be updated atomically, which the
+/// bm-lib cache can't do. Keys live under the API's Redis prefix.
+pub struct Store {
+    conn: ConnectionManager,
+    prefix: String,
+}
+
+/// Outcome of [`Store::compare_and_set`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Swap {
+    Swapped,
+    /// The key holds a different value, e.g. another request swapped it first.
+    Mismatch,
+    Missing,
+}
+
+impl Swap {
+    /// Map the result of [`COMPARE_AND_SET_SCRIPT`].
+    fn from_script_result(result: i64) -> Self {
+        match result {
+            1 => Swap::Swapped,
+            0 => Swap::Mismatch,
+            _ => Swap::Missing,
+        }
+    }
+}
+
+/// `key` under the Redis `prefix`.
+fn prefixed_key(prefix: &str, key: &str) -> String {
+    format!("{}:{}", prefix, key)
+}
+
+/// A held lock, released with [`Store::unlock`].
+#[derive(Debug)]
+pub struct Lock {
+    key: String,
+    token: String,
+}
+
+impl Store {
+    pub async fn connect(redis_uri: &str, prefix: &str) -> RedisResult<Self> {
+        let conn = Client::open(redis_uri)?.get_connection_manager().await?;
+
+        Ok(Self {
+            conn,
+            prefix: prefix.to_string(),
+        })
+    }
+
+    fn key(&self, key: &str) -> String {
+        prefixed_key(&self.prefix, key)
+    }
+
+    /// Set `key` to `value`, expiring after `ttl`.
+    #[instrument(skip(self, value))]
+    pub async fn set(&self, key: &str, value: &str, ttl: Duration) -> RedisResult<()> {
+        let mut conn = self.conn.clone();
+        conn.pset_ex(self.key(key), value, ttl.as_millis() as u64)
+            .await
+    }
+
+    /// Get and delete `key` in one step, so only one caller ever sees its value.
+    #[instrument(skip(self))]
+    pub async fn take(&self, key: &str) -> RedisResult<Option<String>> {
+        let mut conn = self.conn.clone();
+        conn.get_del(self.key(key)).await
+    }
+
+    /// Atomically replace `key`'s value with `new` if it is `expected`,
+    /// resetting its expiry to `ttl`.
+    #[instrument(skip(self, expected, new))]
+    pub async fn compare_and_set(
+        &self,
+        key: &str,
+        expected: &str,
+        new: &str,
+        ttl: Duration,
+    ) -> RedisResult<Swap> {
+        let mut conn = self.conn.clone();
+        let result: i64 = Script::new(COMPARE_AND_SET_SCRIPT)
+            .key(self.key(key))
+            .arg(expected)
+            .arg(new)
+            .arg(ttl.as_millis() as u64)
+            .invoke_async(&mut conn)
+            .await?;
+
+        Ok(Swap::from_script_result(result))
+    }
+
+    /// Prepend `value` to the list `list`.
+    #[instrument(skip(self, value))]
+    pub async fn list_push_front(&self, list: &str, value: &str) -> RedisResult<()> {
+        let mut conn = self.conn.clone();
+        conn.lpush(self.key(list), value).await
+    }
+
+    /// Up to `limit` items of the list `list`, starting at `offset`.
+    #[instrument(skip(self))]
+    pub async fn list_range(
+        &self,
+        list: &str,
+        offset: usize,
+        limit: usize,
+    ) -> RedisResult<Vec<String>> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut conn = self.conn.clone();
+        conn.lrange(
+            self.key(list),
+            offset as isize,
+            (offset + limit - 1) as isize,
+        )
+        .await
+    }
+
+    /// Every item of the list `list`.
+    #[instrument(skip(self))]
+    pub async fn list_all(&self, list: &str) -> RedisResult<Vec<String>> {
+        let mut conn = self.conn.clone();
+        conn.lrange(self.key(list), 0, -1).await
+    }
+
+    /// Increment the counter `key` and return its new value.
+    #[instrument(skip(self))]
+    pub async fn increment(&self, key: &str) -> RedisResult<u64> {
+        let mut conn = self.conn.clone();
+        conn.incr(self.key(key), 1).await
+    }
+
+    /// Set `field` of hash `hash` and append `item` to the list `list`, in one
+    /// transaction. Returns the new length of the list.
+    #[instrument(skip(self, value, item))]
+    pub async fn insert_and_append(
+        &self,
+        hash: &str,
+        field: &str,
+        value: &str,
+        list: &str,
+        item: &str,
+    ) -> RedisResult<usize> {
+        let mut conn = self.conn.clone();
+        let (len,): (usize,) = redis::pipe()
+            .atomic()
+            .hset(self.key(hash), field, value)
+            .ignore()
+            .rpush(self.key(list), item)
+            .query_async(&mut conn)
+            .await?;
+        Ok(len)
+    }
+
+    /// Remove `field` from hash `hash` and the first `item` from the list
+    /// `list`, in one transaction. Undoes [`Store::insert_and_append`].
+    #[instrument(skip(self, item))]
+    pub async fn delete_and_remove(
+        &self,
+        hash: &str,
+        field: &str,
+        list: &str,
+        item: &str,
+    ) -> RedisResult<()> {
+        let mut conn = self.conn.clone();
+        redis::pipe()
+            .atomic()
+            .hdel(self.key(hash), field)
+            .ignore()
+            .lrem(self.key(list), 1, item)
+            .ignore()
+            .query_async(&mut conn)
+            .await
+    }
+
+    /// Remove and return up to `count` items from the front of the list `list`.
+    #[instrument(skip(self))]
+    pub async fn list_pop_front(&self, list: &str, count: usize) -> RedisResult<Vec<String>> {
+        let Some(count) = std::num::NonZeroUsize::new(count) else {
+            return Ok(Vec::new());
+        };
+
+        let mut conn = self.conn.clone();
+        let items: Option<Vec<String>> = conn.lpop(self.key(list), Some(count)).await?;
+        Ok(items.unwrap_or_default())
+    }
+
+    /// Remove `fields` from hash `hash`.
+    #[instrument(skip(self))]
+    pub async fn hash_delete(&self, hash: &str, fields: &[String]) -> RedisResult<()> {
+        if fields.is_empty() {
+            return Ok(());
+        }
+
+        let mut conn = self.conn.clone();
+        conn.hdel(self.key(hash), fields).await
+    }
+
+    /// Get `field` of hash `hash`.
+    #[instrument(skip(self))]
+    pub async fn hash_get(&self, hash: &str, field: &str) -> RedisResult<Option<String>> {
+        let mut conn = self.conn.clone();
+        conn.hget(self.key(hash), field).await
+    }
+
+    /// Set `field` of hash `hash` and add it to the set `index`, in one
+    /// transaction.
+    #[instrument(skip(self, value))]
+    pub async fn insert_indexed(
+        &self,
+        hash: &str,
+        index: &str,
+        field: &str,
+        value: &str,
+    ) -> RedisResult<()> {
+        let mut conn = self.conn.clone();
+        redis::pipe()
+            .atomic()
+            .hset(self.key(hash), field, value)
+            .ignore()
+            .sadd(self.key(index), field)
+            .ignore()
+            .query_async(&mut conn)
+            .await
+    }
+
+    /// Remove `field` from hash `hash` and from the set `index`, in one
+    /// transaction. Returns whether the field existed.
+    #[instrument(skip(self))]
+    pub async fn remove_indexed(&self, hash: &str, index: &str, field: &str) -> RedisResult<bool> {
+        let mut conn = self.conn.clone();
+        let (removed, _): (i64, i64) = redis::pipe()
+            .atomic()
+            .hdel(self.key(hash), field)
+            .srem(self.key(index), field)
+            .query_async(&mut conn)
+            .await?;
+        Ok(removed > 0)
+    }
+
+    /// Values of hash `hash` for every field in the set `index`.
+    #[instrument(skip(self))]
+    pub async fn indexed_values(&self, hash: &str, index: &str) -> RedisResult<Vec<String>> {
+        let mut conn = self.conn.clone();
+        let fields: Vec<String> = conn.smembers(self.key(index)).await?;
+        if fields.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let values: Vec<Option<String>> = redis::cmd("HMGET")
+            .arg(self.key(hash))
+            .arg(&fields)
+            .query_async(&mut conn)
+            .await?;
+        Ok(values.into_iter().flatten().collect())
+    }
+
+    /// Take the lock `key`, waiting up to `wait` for a current holder to
+    /// release it. The lock expires after `ttl` in case its holder never
+    /// releases it. Returns `None` if it couldn't be taken in time.
+    #[instrument(skip(self))]
+    pub async fn lock(
+        &self,
+        key: &str,
+        ttl: Duration,
+        wait: Duration,
+    ) -> RedisResult<Option<Lock>> {
+        let lock = Lock {
+            key: self.key(key),
+            token: crypto::random_token(16),
+        };
+        let options = redis::SetOptions::default()
+            .conditional_set(redis::ExistenceCheck::NX)
+            .with_expiration(redis::SetExpiry::PX(ttl.as_millis() as u64));
+
+        let mut conn = self.conn.clone();
+        let mut waited = Duration::ZERO;
+        loop {
+            let acquired: Option<String> = conn
+                .set_options(&lock.key, &lock.token, options.clone())
+                .await?;
+            if acquired.is_some() {
+                return Ok(Some(lock));
+            }
+            if waited >= wait {
+                return Ok(None);
+            }
+            tokio::time::sleep(LOCK_RETRY_INTERVAL).await;
+            waited += LOCK_RETRY_INTERVAL;
+        }
+    }
+
+    /// Release a lock taken with [`Store::lock`], unless it already expired.
+    #[instrument(skip(self))]
+    pub async fn unlock(&self, lock: Lock) -> RedisResult<()> {
+        let mut conn = self.conn.clone();
+        Script::new(UNLOCK_SCRIPT)
+            .key(&lock.key)
+            .arg(&lock.token)
+            .invoke_async::<()>(&mut conn)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_live_under_the_prefix() {
+        assert_eq!(
+            prefixed_key("mesa-api", "session:abc"),
+            "mesa-api:session:abc"
+        );
+    }
+
+    /// What makes OAuth `state` values single-use.
+    #[tokio::test]
+    #[ignore = "needs a Redis server at REDIS_URI"]
+    async fn taken_values_are_consumed_once() {
+        let uri = std::env::var("REDIS_URI").unwrap_or("redis://localhost:6379".into());
+        let store = Store::connect(&uri, "mesa-api-test").await.unwrap();
+        let key = format!("oauth_state:{}", crypto::random_token(16));
+
+        store
+            .set(&key, "login", Duration::from_secs(60))
+            .await
+            .unwrap();
+
+        assert_eq!(store.take(&key).await.unwrap().as_deref(), Some("login"));
+        assert_eq!(store.take(&key).await.unwrap(), None);
+    }
+
+    #[test]
+    fn compare_and_set_results_map_to_swaps() {
+        assert_eq!(Swap::from_script_result(1), Swap::Swapped);
+        assert_eq!(Swap::from_script_result(0), Swap::Mismatch);
+        assert_eq!(Swap::from_script_result(-1), Swap::Missing);
+    }
+}
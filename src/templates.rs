@@ -0,0 +1,452 @@
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse};
+use bm_lib::{discord::Id, model::Config, permissions::Permission};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::{
+    api::{config_response, require_if_match},
+    auth::AuthenticatedUser,
+    diff::{diff_configs, FieldChange},
+    error::ApiError,
+    export::strip_nulls,
+    validation::{self, FieldError},
+    State,
+};
+
+/// Config new guilds start from, minus the guild ID.
+const DEFAULT_TEMPLATE: &str = include_str!("../templates/default.json");
+
+/// Built-in presets: id, name, description and the partial config they apply.
+const PRESETS: &[(&str, &str, &str, &str)] = &[
+    (
+        "strict-anti-spam",
+        "Strict anti-spam",
+        "Automod spam filter for message floods and long messages, escalating from warn to mute to kick.",
+        include_str!("../templates/presets/strict-anti-spam.json"),
+    ),
+    (
+        "link-free",
+        "Link-free",
+        "Automod censors that remove every link and server invite.",
+        include_str!("../templates/presets/link-free.json"),
+    ),
+];
+
+/// Most templates a user can save.
+const USER_TEMPLATE_LIMIT: usize = 25;
+
+/// Config fields that only make sense in the guild they came from, left out
+/// of saved templates.
+const GUILD_SPECIFIC_FIELDS: &[&str] = &["id", "mute_role", "log_channel", "permission_groups"];
+
+/// Build a config for `guild_id` from a template: a config JSON object
+/// without its `id`.
+pub fn config_from_template(template: &Value, guild_id: &Id) -> Result<Config, ApiError> {
+    let mut value = template.clone();
+    let object = value
+        .as_object_mut()
+        .ok_or_else(|| ApiError::Internal("Config template must be an object".into()))?;
+    object.insert(
+        "id".into(),
+        serde_json::to_value(guild_id)
+            .map_err(|e| ApiError::Internal(format!("Failed to serialize guild ID: {e}")))?,
+    );
+
+    serde_json::from_value(value)
+        .map_err(|e| ApiError::Internal(format!("Invalid config template: {e}")))
+}
+
+/// The default config for a guild that hasn't been set up yet.
+pub fn default_config(guild_id: &Id) -> Result<Config, ApiError> {
+    let template: Value = serde_json::from_str(DEFAULT_TEMPLATE)
+        .map_err(|e| ApiError::Internal(format!("Invalid default config template: {e}")))?;
+    config_from_template(&template, guild_id)
+}
+
+/// A reusable set of config values, applied to a guild's config as a JSON
+/// merge patch: fields it sets are overwritten, everything else is kept.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConfigTemplate {
+    pub id: String,
+    pub name: String,
+    pub description: Option<String>,
+    pub builtin: bool,
+    /// Guild a saved template was created from.
+    pub source_guild: Option<Id>,
+    /// Unix timestamp (seconds) a saved template was created at.
+    pub created_at: Option<i64>,
+    pub config: Value,
+}
+
+fn presets() -> Result<Vec<ConfigTemplate>, ApiError> {
+    PRESETS
+        .iter()
+        .map(|(id, name, description, config)| {
+            Ok(ConfigTemplate {
+                id: id.to_string(),
+                name: name.to_string(),
+                description: Some(description.to_string()),
+                builtin: true,
+                source_guild: None,
+                created_at: None,
+                config: serde_json::from_str(config).map_err(|e| {
+                    ApiError::Internal(format!("Invalid preset template {id}: {e}"))
+                })?,
+            })
+        })
+        .collect()
+}
+
+/// A template's values from a guild's config, without guild-specific fields
+/// or per-channel automod overrides, so it can be applied to any guild.
+fn template_values(config: &Config) -> Result<Value, ApiError> {
+    let mut value = serde_json::to_value(config)
+        .map_err(|e| ApiError::Internal(format!("Failed to serialize config: {e}")))?;
+
+    if let Some(object) = value.as_object_mut() {
+        for field in GUILD_SPECIFIC_FIELDS {
+            object.remove(*field);
+        }
+        if let Some(automod) = object.get_mut("automod").and_then(Value::as_object_mut) {
+            automod.remove("channels");
+        }
+    }
+
+    // A null in a merge patch deletes the field, so unset values are left out
+    // rather than clearing them in the target.
+    strip_nulls(&mut value);
+    Ok(value)
+}
+
+/// Apply a template to `config`.
+fn apply_template(config: &Config, template: &ConfigTemplate) -> Result<Config, ApiError> {
+    let mut value = serde_json::to_value(config)
+        .map_err(|e| ApiError::Internal(format!("Failed to serialize config: {e}")))?;
+    json_patch::merge(&mut value, &template.config);
+
+    let updated: Config = serde_json::from_value(value).map_err(|e| {
+        ApiError::BadRequest(format!(
+            "Template {} produces an invalid config: {e}",
+            template.id
+        ))
+    })?;
+    if updated.id != config.id {
+        return Err(ApiError::BadRequest(
+            "Template cannot change the config ID".into(),
+        ));
+    }
+
+    Ok(updated)
+}
+
+#[inline]
+fn user_templates_cache_key(user_id: &Id) -> String {
+    format!("config_templates:{}", user_id)
+}
+
+impl State {
+    /// Templates a user has saved, oldest first.
+    #[instrument(skip(self))]
+    pub async fn get_user_templates(&self, user_id: &Id) -> Result<Vec<ConfigTemplate>, ApiError> {
+        let key = user_templates_cache_key(user_id);
+        Ok(self
+            .cache
+            .get::<String, Vec<ConfigTemplate>>(&key)
+            .await?
+            .unwrap_or_default())
+    }
+
+    /// A built-in preset, or one of the user's saved templates.
+    #[instrument(skip(self))]
+    pub async fn get_template(
+        &self,
+        user_id: &Id,
+        template_id: &str,
+    ) -> Result<ConfigTemplate, ApiError> {
+        presets()?
+            .into_iter()
+            .chain(self.get_user_templates(user_id).await?)
+            .find(|template| template.id == template_id)
+            .ok_or_else(|| ApiError::NotFound("Template not found".into()))
+    }
+
+    #[instrument(skip(self, config))]
+    pub async fn save_user_template(
+        &self,
+        user_id: &Id,
+        name: &str,
+        description: Option<String>,
+        config: &Config,
+    ) -> Result<ConfigTemplate, ApiError> {
+        let mut templates = self.get_user_templates(user_id).await?;
+        if templates.len() >= USER_TEMPLATE_LIMIT {
+            return Err(ApiError::BadRequest(format!(
+                "At most {} templates can be saved",
+                USER_TEMPLATE_LIMIT
+            )));
+        }
+
+        let template = ConfigTemplate {
+            id: Uuid::new_v4().simple().to_string(),
+            name: name.to_string(),
+            description,
+            builtin: false,
+            source_guild: Some(config.id),
+            created_at: Some(Utc::now().timestamp()),
+            config: template_values(config)?,
+        };
+
+        templates.push(template.clone());
+        self.cache
+            .set(&user_templates_cache_key(user_id), &templates, None)
+            .await?;
+
+        Ok(template)
+    }
+
+    #[instrument(skip(self))]
+    pub async fn delete_user_template(
+        &self,
+        user_id: &Id,
+        template_id: &str,
+    ) -> Result<bool, ApiError> {
+        let mut templates = self.get_user_templates(user_id).await?;
+        let before = templates.len();
+        templates.retain(|template| template.id != template_id);
+        if templates.len() == before {
+            return Ok(false);
+        }
+
+        self.cache
+            .set(&user_templates_cache_key(user_id), &templates, None)
+            .await?;
+
+        Ok(true)
+    }
+}
+
+/// `GET /api/templates` - built-in presets followed by the user's saved templates.
+#[get("/api/templates")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn list_templates(
+    state: web::Data<State>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<Vec<ConfigTemplate>>, ApiError> {
+    let mut templates = presets()?;
+    templates.extend(state.get_user_templates(&user.user_id).await?);
+
+    Ok(web::Json(templates))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SaveTemplateRequest {
+    pub name: String,
+    pub description: Option<String>,
+    /// Guild whose config the template is created from.
+    pub guild_id: String,
+}
+
+/// `POST /api/templates` - save a template from a guild's config. Requires
+/// `CONFIG_VIEW` on that guild.
+#[post("/api/templates")]
+#[instrument(skip(state, user, body), fields(user_id = %user.user_id))]
+pub async fn save_template(
+    state: web::Data<State>,
+    body: web::Json<SaveTemplateRequest>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let body = body.into_inner();
+    let guild_id = Id::from_str(&body.guild_id)
+        .map_err(|_| ApiError::ParseError("Invalid guild ID".into()))?;
+
+    let name = body.name.trim();
+    if name.is_empty() {
+        return Err(ApiError::BadRequest("Template name is required".into()));
+    }
+
+    let (_, config) = state
+        .require_guild_permission(&user, &guild_id, Permission::CONFIG_VIEW)
+        .await?;
+
+    let template = state
+        .save_user_template(&user.user_id, name, body.description, &config)
+        .await?;
+
+    Ok(HttpResponse::Created().json(template))
+}
+
+/// `DELETE /api/templates/{template_id}` - delete one of the user's saved templates.
+#[delete("/api/templates/{template_id}")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn delete_template(
+    state: web::Data<State>,
+    template_id: web::Path<String>,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    if !state
+        .delete_user_template(&user.user_id, &template_id)
+        .await?
+    {
+        return Err(ApiError::NotFound("Template not found".into()));
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[derive(Debug, Serialize)]
+pub struct TemplatePreview {
+    pub changes: Vec<FieldChange>,
+    /// Validation errors the result would have; applying fails unless empty.
+    pub errors: Vec<FieldError>,
+}
+
+/// `POST /api/config/{id}/templates/{template_id}/preview` - what applying a
+/// template would change, and whether the result is valid, without saving.
+#[post("/api/config/{id}/templates/{template_id}/preview")]
+#[instrument(skip(state, user), fields(user_id = %user.user_id))]
+pub async fn preview_template(
+    state: web::Data<State>,
+    path: web::Path<(String, String)>,
+    user: AuthenticatedUser,
+) -> Result<web::Json<TemplatePreview>, ApiError> {
+    let (id, template_id) = path.into_inner();
+    let id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".to_string()))?;
+
+    let (guild, config) = state
+        .require_guild_permission(&user, &id, Permission::CONFIG_VIEW)
+        .await?;
+    let template = state.get_template(&user.user_id, &template_id).await?;
+
+    let updated = apply_template(&config, &template)?;
+    let channels = state.guild_channels(&id).await?;
+    let commands = state.get_bot_commands().await?;
+
+    Ok(web::Json(TemplatePreview {
+        changes: diff_configs(&config, &updated)?,
+        errors: validation::validate_config(&updated, &guild, &channels, &commands),
+    }))
+}
+
+/// `POST /api/config/{id}/templates/{template_id}/apply` - apply a template to
+/// a guild's config. Validated and guarded by `If-Match` like `POST /api/config/{id}`.
+#[post("/api/config/{id}/templates/{template_id}/apply")]
+#[instrument(skip(state, user, req), fields(user_id = %user.user_id))]
+pub async fn apply_template_to_config(
+    state: web::Data<State>,
+    path: web::Path<(String, String)>,
+    req: HttpRequest,
+    user: AuthenticatedUser,
+) -> Result<HttpResponse, ApiError> {
+    let (id, template_id) = path.into_inner();
+    let id = Id::from_str(&id).map_err(|_| ApiError::ParseError("Invalid ID".to_string()))?;
+
+    let (guild, config) = state
+        .require_guild_permission(&user, &id, Permission::CONFIG_EDIT)
+        .await?;
+    require_if_match(&req, &config)?;
+
+    let template = state.get_template(&user.user_id, &template_id).await?;
+    let update = apply_template(&config, &template)?;
+    state.validate_config(&update, &guild).await?;
+    state
+        .check_escalation(&guild, &config, &update, &user)
+        .await?;
+
+    let reason = format!("Applied template {}", template.name);
+    let updated = state
+        .update_config(&id, &config, &update, &user.user_id, Some(&reason))
+        .await?;
+
+    config_response(&updated)
+}
+
+#[cfg(test)]
+mod tests {
+    use bm_lib::discord::Guild;
+    use serde_json::json;
+
+    use super::*;
+
+    fn id(n: u64) -> Id {
+        Id::from_str(&n.to_string()).unwrap()
+    }
+
+    fn guild() -> Guild {
+        serde_json::from_value(json!({
+            "id": id(1),
+            "name": "guild",
+            "icon": null,
+            "owner_id": null,
+            "roles": [],
+            "member_count": null,
+            "approximate_member_count": null,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        let config = default_config(&id(1)).unwrap();
+
+        assert_eq!(config.id, id(1));
+        assert!(validation::validate_config(&config, &guild(), &[], &[]).is_empty());
+    }
+
+    #[test]
+    fn every_preset_is_listed_and_valid() {
+        let presets = presets().unwrap();
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/templates/presets");
+
+        let mut files = 0;
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            let preset_id = path.file_stem().unwrap().to_str().unwrap();
+            let preset = presets
+                .iter()
+                .find(|preset| preset.id == preset_id)
+                .unwrap_or_else(|| panic!("{} is not in PRESETS", path.display()));
+
+            let file: Value =
+                serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            assert_eq!(file, preset.config, "{preset_id}");
+
+            let config = apply_template(&default_config(&id(1)).unwrap(), preset).unwrap();
+            let errors = validation::validate_config(&config, &guild(), &[], &[]);
+            assert!(errors.is_empty(), "{preset_id}: {errors:?}");
+            files += 1;
+        }
+        assert_eq!(files, presets.len());
+    }
+
+    #[test]
+    fn template_values_leave_out_guild_specific_fields() {
+        let mut config = default_config(&id(1)).unwrap();
+        config.mute_role = Some(id(10));
+        config.log_channel = Some(id(20));
+
+        let values = template_values(&config).unwrap();
+        for field in GUILD_SPECIFIC_FIELDS {
+            assert!(values.get(field).is_none(), "{field}");
+        }
+        assert_eq!(values["prefix"], "!");
+    }
+
+    #[test]
+    fn templates_cannot_change_the_config_id() {
+        let template = ConfigTemplate {
+            id: "t".into(),
+            name: "t".into(),
+            description: None,
+            builtin: false,
+            source_guild: None,
+            created_at: None,
+            config: json!({ "id": id(2), "prefix": "?" }),
+        };
+
+        let config = default_config(&id(1)).unwrap();
+        assert!(apply_template(&config, &template).is_err());
+    }
+}
@@ -0,0 +1,533 @@
+use bm_lib::{
+    discord::{Channel, Guild, Id},
+    model::{
+        automod::{Automod, AutomodSettings},
+        logging::{LogConfig, LogEventType},
+        Config,
+    },
+};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::instrument;
+
+use crate::{diff::pointer_push, error::ApiError, State};
+
+/// Channel types config may post to: `GUILD_TEXT` and `GUILD_ANNOUNCEMENT`.
+const TEXT_CHANNEL_TYPES: [u64; 2] = [0, 5];
+
+const MAX_PREFIX_LEN: usize = 10;
+const MAX_ALIAS_LEN: usize = 32;
+const MAX_FILTER_LEN: usize = 200;
+/// Seconds.
+const MAX_WARN_DURATION: u64 = 5 * 365 * 24 * 60 * 60;
+/// Milliseconds, as used by automod actions.
+const MAX_ACTION_DURATION: u64 = 365 * 24 * 60 * 60 * 1000;
+/// Milliseconds.
+const MAX_SPAM_INTERVAL: u64 = 60 * 60 * 1000;
+const MAX_SPAM_COUNT: u64 = 100;
+const MAX_SPAM_THRESHOLD: u64 = 100;
+
+/// A problem with one config field.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+    /// JSON Pointer to the field, like the paths in [`crate::diff::FieldChange`].
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Default)]
+struct Errors(Vec<FieldError>);
+
+impl Errors {
+    fn add(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.0.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+}
+
+impl State {
+    /// Check `config` against the guild it belongs to before it is saved.
+    /// Returns [`ApiError::Validation`] listing every invalid field.
+    #[instrument(skip(self, config, guild), fields(guild_id = %guild.id))]
+    pub async fn validate_config(&self, config: &Config, guild: &Guild) -> Result<(), ApiError> {
+        let channels = self.guild_channels(&guild.id).await?;
+        let commands = self.get_bot_commands().await?;
+
+        let errors = validate_config(config, guild, &channels, &commands);
+        if !errors.is_empty() {
+            return Err(ApiError::Validation(errors));
+        }
+
+        Ok(())
+    }
+}
+
+/// Validate `config` against the guild's roles and channels, and its aliases
+/// against the bot's `commands`. Aliases aren't checked against commands while
+/// the bot hasn't published any.
+pub fn validate_config(
+    config: &Config,
+    guild: &Guild,
+    channels: &[Channel],
+    commands: &[String],
+) -> Vec<FieldError> {
+    let mut errors = Errors::default();
+
+    let prefix = config.prefix.trim();
+    if prefix.is_empty() {
+        errors.add("/prefix", "must not be empty");
+    } else if prefix.chars().count() > MAX_PREFIX_LEN {
+        errors.add(
+            "/prefix",
+            format!("must be at most {} characters", MAX_PREFIX_LEN),
+        );
+    } else if prefix != config.prefix {
+        errors.add("/prefix", "must not start or end with whitespace");
+    }
+
+    if let Some(role) = &config.mute_role {
+        if !guild.roles.iter().any(|r| r.id == *role) {
+            errors.add("/mute_role", "role does not exist in this guild");
+        }
+    }
+
+    if config
+        .default_warn_duration
+        .is_some_and(|duration| duration > MAX_WARN_DURATION)
+    {
+        errors.add(
+            "/default_warn_duration",
+            format!("must be at most {} seconds", MAX_WARN_DURATION),
+        );
+    }
+
+    if let Some(channel) = &config.log_channel {
+        check_text_channel(&mut errors, "/log_channel", channel, channels);
+    }
+
+    if let Some(groups) = &config.permission_groups {
+        for (i, group) in groups.iter().enumerate() {
+            let path = format!("/permission_groups/{i}");
+            if group.name.trim().is_empty() {
+                errors.add(format!("{path}/name"), "must not be empty");
+            }
+            if groups[..i].iter().any(|other| other.name == group.name) {
+                errors.add(format!("{path}/name"), "duplicate group name");
+            }
+            for (j, role) in group.roles.iter().enumerate() {
+                if !guild.roles.iter().any(|r| r.id == *role) {
+                    errors.add(
+                        format!("{path}/roles/{j}"),
+                        format!("role {} does not exist in this guild", role),
+                    );
+                }
+            }
+        }
+    }
+
+    if let Some(aliases) = &config.command_aliases {
+        let mut aliases: Vec<_> = aliases.iter().collect();
+        aliases.sort();
+        for (alias, command) in aliases {
+            let field = pointer_push("/command_aliases", alias);
+            if alias.is_empty()
+                || alias.chars().count() > MAX_ALIAS_LEN
+                || alias.contains(char::is_whitespace)
+            {
+                errors.add(
+                    &field,
+                    format!(
+                        "alias must be 1-{} characters without whitespace",
+                        MAX_ALIAS_LEN
+                    ),
+                );
+            }
+            if commands.is_empty() {
+                continue;
+            }
+            if commands.contains(alias) {
+                errors.add(&field, "alias would shadow a built-in command");
+            }
+            if !commands.contains(command) {
+                errors.add(&field, format!("unknown command `{}`", command));
+            }
+        }
+    }
+
+    if let Some(automod) = &config.automod {
+        validate_automod(&mut errors, automod, channels);
+    }
+
+    errors.0
+}
+
+/// Validate log configs: known event types posting to text channels in the guild.
+pub fn validate_log_configs(logs: &[LogConfig], channels: &[Channel]) -> Vec<FieldError> {
+    let mut errors = Errors::default();
+
+    for log in logs {
+        let field = pointer_push("/logging", &log.event);
+        if LogEventType::from_db_key(&log.event).is_none() {
+            errors.add(&field, "unknown log event type");
+        }
+        if let Some(channel) = &log.channel_id {
+            check_text_channel(
+                &mut errors,
+                &format!("{field}/channel_id"),
+                channel,
+                channels,
+            );
+        }
+    }
+
+    errors.0
+}
+
+fn check_text_channel(errors: &mut Errors, field: &str, channel_id: &Id, channels: &[Channel]) {
+    let Some(channel) = channels.iter().find(|c| c.id == *channel_id) else {
+        errors.add(field, "channel does not exist in this guild");
+        return;
+    };
+
+    let kind = serde_json::to_value(channel)
+        .ok()
+        .and_then(|value| value.get("type").and_then(Value::as_u64));
+    if !kind.is_some_and(|kind| TEXT_CHANNEL_TYPES.contains(&kind)) {
+        errors.add(field, "must be a text channel");
+    }
+}
+
+fn validate_automod(errors: &mut Errors, automod: &Automod, channels: &[Channel]) {
+    if let Some(global) = &automod.global {
+        validate_automod_settings(errors, "/automod/global", global);
+    }
+
+    let mut overrides: Vec<_> = automod.channels.iter().collect();
+    overrides.sort_by_key(|(channel_id, _)| **channel_id);
+    for (channel_id, settings) in overrides {
+        let field = format!("/automod/channels/{channel_id}");
+        check_text_channel(errors, &field, channel_id, channels);
+        validate_automod_settings(errors, &field, settings);
+    }
+}
+
+fn validate_automod_settings(errors: &mut Errors, path: &str, settings: &AutomodSettings) {
+    let mut censors: Vec<_> = settings
+        .censors
+        .iter()
+        .map(|(kind, censor)| (kind.to_string(), censor))
+        .collect();
+    censors.sort_by(|a, b| a.0.cmp(&b.0));
+    for (kind, censor) in censors {
+        let path = format!("{path}/censors/{kind}");
+        for (i, filter) in censor.filters.iter().enumerate() {
+            if filter.trim().is_empty() || filter.chars().count() > MAX_FILTER_LEN {
+                errors.add(
+                    format!("{path}/filters/{i}"),
+                    format!("must be 1-{} characters", MAX_FILTER_LEN),
+                );
+            }
+        }
+        check_range(
+            errors,
+            &format!("{path}/action/duration"),
+            censor.action.duration,
+            0,
+            MAX_ACTION_DURATION,
+        );
+    }
+
+    let Some(spam) = &settings.spam else {
+        return;
+    };
+    let path = format!("{path}/spam");
+
+    let mut filters: Vec<_> = spam
+        .filters
+        .iter()
+        .map(|(kind, filter)| (kind.to_string(), filter))
+        .collect();
+    filters.sort_by(|a, b| a.0.cmp(&b.0));
+    for (kind, filter) in filters {
+        let path = format!("{path}/filters/{kind}");
+        check_range(
+            errors,
+            &format!("{path}/interval"),
+            filter.interval,
+            1,
+            MAX_SPAM_INTERVAL,
+        );
+        check_range(
+            errors,
+            &format!("{path}/count"),
+            filter.count,
+            1,
+            MAX_SPAM_COUNT,
+        );
+    }
+
+    for (i, action) in spam.action.iter().enumerate() {
+        let path = format!("{path}/action/{i}");
+        check_range(
+            errors,
+            &format!("{path}/duration"),
+            action.duration,
+            0,
+            MAX_ACTION_DURATION,
+        );
+        check_range(
+            errors,
+            &format!("{path}/threshold"),
+            action.threshold,
+            1,
+            MAX_SPAM_THRESHOLD,
+        );
+
+        if spam.action[..i]
+            .iter()
+            .any(|other| other.threshold == action.threshold)
+        {
+            errors.add(
+                format!("{path}/threshold"),
+                "another action already uses this threshold",
+            );
+        }
+    }
+}
+
+fn check_range(errors: &mut Errors, field: &str, value: u64, min: u64, max: u64) {
+    if !(min..=max).contains(&value) {
+        errors.add(field, format!("must be an integer from {} to {}", min, max));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn id(n: u64) -> Id {
+        Id::from_str(&n.to_string()).unwrap()
+    }
+
+    const MUTE_ROLE: u64 = 10;
+    const TEXT_CHANNEL: u64 = 20;
+    const VOICE_CHANNEL: u64 = 21;
+
+    fn guild() -> Guild {
+        serde_json::from_value(json!({
+            "id": id(1),
+            "name": "guild",
+            "icon": null,
+            "owner_id": id(2),
+            "roles": [
+                { "id": id(MUTE_ROLE), "name": "muted", "position": 1, "permissions": 0, "managed": false },
+            ],
+            "member_count": null,
+            "approximate_member_count": null,
+        }))
+        .unwrap()
+    }
+
+    fn channels() -> Vec<Channel> {
+        serde_json::from_value(json!([
+            { "id": id(TEXT_CHANNEL), "name": "general", "type": 0 },
+            { "id": id(VOICE_CHANNEL), "name": "voice", "type": 2 },
+        ]))
+        .unwrap()
+    }
+
+    fn config() -> Config {
+        serde_json::from_value(json!({
+            "id": id(1),
+            "prefix": "!",
+            "mute_role": id(MUTE_ROLE),
+            "default_warn_duration": 86400,
+            "log_channel": id(TEXT_CHANNEL),
+            "prefer_embeds": true,
+            "inherit_discord_perms": true,
+            "alert_on_infraction": true,
+            "send_permission_denied": true,
+            "moderation_enabled": true,
+            "music_enabled": false,
+            "automod_enabled": true,
+            "permission_groups": null,
+            "automod": null,
+            "command_aliases": { "w": "warn" },
+        }))
+        .unwrap()
+    }
+
+    fn spam_automod(interval: u64, count: u64, thresholds: &[u64]) -> Automod {
+        let actions: Vec<_> = thresholds
+            .iter()
+            .map(|threshold| json!({ "action": "warn", "duration": 0, "threshold": threshold }))
+            .collect();
+        serde_json::from_value(json!({
+            "global": {
+                "name": "global",
+                "enabled": true,
+                "spam": {
+                    "enabled": true,
+                    "filters": { "message": { "interval": interval, "count": count } },
+                    "action": actions,
+                },
+            },
+        }))
+        .unwrap()
+    }
+
+    fn commands() -> Vec<String> {
+        ["ban", "kick", "warn"].map(String::from).to_vec()
+    }
+
+    fn fields(config: &Config) -> Vec<String> {
+        validate_config(config, &guild(), &channels(), &commands())
+            .into_iter()
+            .map(|error| error.field)
+            .collect()
+    }
+
+    #[test]
+    fn valid_config_has_no_errors() {
+        let mut config = config();
+        config.automod = Some(spam_automod(5000, 5, &[1, 3]));
+
+        assert!(fields(&config).is_empty());
+    }
+
+    #[test]
+    fn rejects_missing_mute_role() {
+        let mut config = config();
+        config.mute_role = Some(id(99));
+
+        assert_eq!(fields(&config), ["/mute_role"]);
+    }
+
+    #[test]
+    fn log_channel_must_be_an_existing_text_channel() {
+        let mut config = config();
+        config.log_channel = Some(id(VOICE_CHANNEL));
+        assert_eq!(fields(&config), ["/log_channel"]);
+
+        config.log_channel = Some(id(99));
+        assert_eq!(fields(&config), ["/log_channel"]);
+    }
+
+    #[test]
+    fn rejects_empty_long_and_padded_prefixes() {
+        for prefix in ["", "   ", "12345678901", " !"] {
+            let mut config = config();
+            config.prefix = prefix.to_string();
+
+            assert_eq!(fields(&config), ["/prefix"], "prefix {prefix:?}");
+        }
+    }
+
+    fn aliases(aliases: &[(&str, &str)]) -> Config {
+        let mut config = config();
+        config.command_aliases = Some(
+            aliases
+                .iter()
+                .map(|(alias, command)| (alias.to_string(), command.to_string()))
+                .collect(),
+        );
+        config
+    }
+
+    #[test]
+    fn rejects_bad_aliases() {
+        let config = aliases(&[
+            ("b", "unknown"),
+            ("ban", "kick"),
+            ("two words", "warn"),
+            ("a/b", "unknown"),
+        ]);
+
+        assert_eq!(
+            fields(&config),
+            [
+                "/command_aliases/a~1b",
+                "/command_aliases/b",
+                "/command_aliases/ban",
+                "/command_aliases/two words",
+            ]
+        );
+    }
+
+    #[test]
+    fn alias_length_counts_characters() {
+        let config = aliases(&[(&"é".repeat(MAX_ALIAS_LEN), "warn")]);
+        assert!(fields(&config).is_empty());
+
+        let config = aliases(&[(&"é".repeat(MAX_ALIAS_LEN + 1), "warn")]);
+        assert_eq!(fields(&config).len(), 1);
+    }
+
+    #[test]
+    fn aliases_are_only_checked_against_published_commands() {
+        let config = aliases(&[("b", "unknown"), ("ban", "kick")]);
+
+        assert!(validate_config(&config, &guild(), &channels(), &[]).is_empty());
+    }
+
+    #[test]
+    fn rejects_out_of_range_spam_values() {
+        let mut config = config();
+        config.automod = Some(spam_automod(0, MAX_SPAM_COUNT + 1, &[0]));
+
+        assert_eq!(
+            fields(&config),
+            [
+                "/automod/global/spam/filters/message/interval",
+                "/automod/global/spam/filters/message/count",
+                "/automod/global/spam/action/0/threshold",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_duplicate_spam_thresholds() {
+        let mut config = config();
+        config.automod = Some(spam_automod(5000, 5, &[1, 3, 1]));
+
+        assert_eq!(fields(&config), ["/automod/global/spam/action/2/threshold"]);
+    }
+
+    #[test]
+    fn checks_censors_and_channel_overrides() {
+        let mut config = config();
+        config.automod = Some(
+            serde_json::from_value(json!({
+                "channels": {
+                    VOICE_CHANNEL.to_string(): {
+                        "name": "voice",
+                        "enabled": true,
+                        "censors": {
+                            "word": {
+                                "enabled": true,
+                                "filters": ["ok", " ", "x".repeat(MAX_FILTER_LEN + 1)],
+                                "action": { "action": "mute", "duration": MAX_ACTION_DURATION + 1 },
+                            },
+                        },
+                    },
+                },
+            }))
+            .unwrap(),
+        );
+
+        let field = format!("/automod/channels/{VOICE_CHANNEL}");
+        assert_eq!(
+            fields(&config),
+            [
+                field.clone(),
+                format!("{field}/censors/word/filters/1"),
+                format!("{field}/censors/word/filters/2"),
+                format!("{field}/censors/word/action/duration"),
+            ]
+        );
+    }
+}